@@ -0,0 +1,93 @@
+//! Throughput of the database with one shard against the default sharding, as more threads hit
+//! it at once. Run with `cargo bench`.
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use rscache::{
+    config::Config,
+    database::{Database, StoreMode},
+};
+
+/// Keys the workers pick from.
+const KEYS: usize = 10_000;
+/// Operations every worker runs.
+const OPS: usize = 200_000;
+/// One in this many operations is a write, the rest are reads.
+const WRITE_EVERY: usize = 10;
+
+fn main() {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let workers = std::iter::successors(Some(1), |n| Some(n * 2))
+        .take_while(|n| *n <= cores.max(4))
+        .collect::<Vec<usize>>();
+    let default_shards = config(None).shards();
+
+    println!("{} cores, {} ops per worker", cores, OPS);
+    println!("{:>8} {:>8} {:>14}", "shards", "workers", "ops/s");
+    for shards in [1, default_shards] {
+        for &n in &workers {
+            let ops = run(shards, n);
+            println!("{:>8} {:>8} {:>14.0}", shards, n, ops);
+        }
+    }
+}
+
+fn config(shards: Option<usize>) -> Config {
+    let json = match shards {
+        Some(n) => format!(r#"{{ "shards": {} }}"#, n),
+        None => "{}".to_string(),
+    };
+    serde_json::from_str(&json).expect("valid config")
+}
+
+/// Operations per second over all workers.
+fn run(shards: usize, workers: usize) -> f64 {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(workers)
+        .build()
+        .expect("runtime");
+
+    runtime.block_on(async {
+        let db = Database::new(Arc::new(config(Some(shards))));
+        let keys = Arc::new((0..KEYS).map(|i| format!("key:{}", i)).collect::<Vec<_>>());
+        for key in keys.iter() {
+            db.store(key.clone(), vec![0; 64], 0, None, StoreMode::Set)
+                .await;
+        }
+
+        let start = Instant::now();
+        let tasks = (0..workers)
+            .map(|_| {
+                let db = db.clone();
+                let keys = Arc::clone(&keys);
+                tokio::spawn(async move { worker(db, &keys).await })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.expect("worker panicked");
+        }
+        let elapsed = start.elapsed().max(Duration::from_nanos(1));
+
+        (workers * OPS) as f64 / elapsed.as_secs_f64()
+    })
+}
+
+async fn worker(db: Database, keys: &[String]) {
+    let picks = {
+        let mut rng = rand::thread_rng();
+        (0..OPS)
+            .map(|_| rng.gen_range(0..keys.len()))
+            .collect::<Vec<_>>()
+    };
+    for (i, idx) in picks.into_iter().enumerate() {
+        let key = keys[idx].clone();
+        if i % WRITE_EVERY == 0 {
+            db.store(key, vec![1; 64], 0, None, StoreMode::Set).await;
+        } else {
+            db.get_or_remove(key).await;
+        }
+    }
+}
@@ -0,0 +1,471 @@
+use std::{
+    io::{self, ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
+    sync::Mutex,
+};
+
+use crate::{
+    config::FsyncPolicy,
+    database::{until_unix, Database},
+    frame::MAX_VALUE_LEN,
+};
+
+/// Start of every log file.
+const MAGIC: &[u8; 8] = b"RSCAOF\0\0";
+/// Bumped whenever the record layout below changes, older versions are refused on replay.
+const VERSION: u16 = 1;
+/// The log is compacted once it's grown to this many times its size after the last compaction.
+const COMPACT_GROWTH: u64 = 2;
+/// Logs smaller than this are never compacted, it's not worth the rewrite.
+const COMPACT_MIN_SIZE: u64 = 1024 * 1024;
+/// How often the background task checks whether the log needs compacting.
+const COMPACT_CHECK: Duration = Duration::from_secs(10);
+
+const OP_SET: u8 = 1;
+const OP_DEL: u8 = 2;
+const OP_FLUSH: u8 = 3;
+
+// Layout, integers big endian:
+//
+//   MAGIC VERSION:u16
+//   then records, either
+//     OP_SET KEY_LEN:u32 KEY FLAGS:u32 EXPIRES_AT_MILLIS:i64 VALUE_LEN:u32 VALUE
+//     OP_DEL KEY_LEN:u32 KEY
+//     OP_FLUSH
+//
+// EXPIRES_AT_MILLIS is a unix time, -1 never expires. A set record holds the whole entry, so
+// replaying a record twice changes nothing - compaction relies on that.
+
+/// Every change made to the database, appended to a file so it can be replayed after a restart.
+/// With it configured, the log is what the database is restored from instead of the snapshot.
+#[derive(Debug, Clone)]
+pub struct AppendLog {
+    inner: Arc<Mutex<Inner>>,
+    fsync: FsyncPolicy,
+    // written since the last fsync, for `FsyncPolicy::EverySec`
+    dirty: Arc<AtomicBool>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    // size right after the last compaction, or at startup
+    base_size: u64,
+    // records appended while a compaction is running, they go into the compacted log too
+    rewrite: Option<Vec<u8>>,
+}
+
+impl AppendLog {
+    /// Replays the log at `path` into `db` and opens it for appending, creating it if needed.
+    /// A record cut short by a crash is dropped from the file. Returns the log and how many
+    /// records were replayed.
+    pub async fn open(path: &Path, fsync: FsyncPolicy, db: &Database) -> io::Result<(Self, usize)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .await?;
+
+        let (replayed, size) = match file.metadata().await?.len() {
+            0 => {
+                file.write_all(&header()).await?;
+                file.sync_all().await?;
+                (0, header().len() as u64)
+            }
+            _ => replay(&mut file, db).await?,
+        };
+        file.set_len(size).await?;
+        file.seek(SeekFrom::Start(size)).await?;
+
+        let log = Self {
+            inner: Arc::new(Mutex::new(Inner {
+                path: path.to_path_buf(),
+                file,
+                size,
+                base_size: size,
+                rewrite: None,
+            })),
+            fsync,
+            dirty: Arc::new(AtomicBool::new(false)),
+        };
+        log.spawn_background();
+        Ok((log, replayed))
+    }
+
+    /// Records that `key` now holds `value`.
+    pub async fn append_set(
+        &self,
+        key: &str,
+        value: &[u8],
+        ttl: Option<Duration>,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.append(encode_set(key, value, ttl, flags)).await
+    }
+
+    /// Records that `key` was removed.
+    pub async fn append_del(&self, key: &str) -> io::Result<()> {
+        let mut record = vec![OP_DEL];
+        push_bytes(&mut record, key.as_bytes());
+        self.append(record).await
+    }
+
+    /// Records that every key was removed.
+    pub async fn append_flush(&self) -> io::Result<()> {
+        self.append(vec![OP_FLUSH]).await
+    }
+
+    async fn append(&self, record: Vec<u8>) -> io::Result<()> {
+        let mut inner = self.inner.lock().await;
+        inner.file.write_all(&record).await?;
+        inner.size += record.len() as u64;
+        if let Some(ref mut rewrite) = inner.rewrite {
+            rewrite.extend_from_slice(&record);
+        }
+
+        match self.fsync {
+            FsyncPolicy::Always => inner.file.sync_data().await?,
+            FsyncPolicy::EverySec => self.dirty.store(true, Ordering::Relaxed),
+            FsyncPolicy::No => {}
+        }
+        Ok(())
+    }
+
+    /// Fsyncs once a second for `FsyncPolicy::EverySec`, and compacts the log as it grows.
+    fn spawn_background(&self) {
+        if self.fsync == FsyncPolicy::EverySec {
+            let log = self.clone();
+            tokio::task::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(1));
+                loop {
+                    interval.tick().await;
+                    if log.dirty.swap(false, Ordering::Relaxed) {
+                        if let Err(err) = log.inner.lock().await.file.sync_data().await {
+                            tracing::error!(message = "Could not fsync the append-only log", %err);
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    /// Compacts the log in the background whenever it has grown enough since the last time.
+    pub fn compact_periodically(&self, db: Database) {
+        let log = self.clone();
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(COMPACT_CHECK);
+            loop {
+                interval.tick().await;
+                let due = {
+                    let inner = log.inner.lock().await;
+                    inner.size >= COMPACT_MIN_SIZE
+                        && inner.size >= inner.base_size.saturating_mul(COMPACT_GROWTH)
+                };
+                if !due {
+                    continue;
+                }
+                match log.compact(&db).await {
+                    Ok(size) => tracing::info!(message = "Compacted the append-only log", size),
+                    Err(err) => {
+                        tracing::error!(message = "Could not compact the append-only log", %err)
+                    }
+                }
+            }
+        });
+    }
+
+    /// Rewrites the log as one set record per live entry. Appends carry on meanwhile, they are
+    /// collected and added to the end of the new log before it replaces the old one.
+    pub async fn compact(&self, db: &Database) -> io::Result<u64> {
+        let tmp = self.start_rewrite().await;
+        let result = self.rewrite(db, &tmp).await;
+        self.finish_rewrite(&tmp, result).await
+    }
+
+    /// Starts collecting appends for the new log, returns the path to write it to.
+    async fn start_rewrite(&self) -> PathBuf {
+        let mut inner = self.inner.lock().await;
+        inner.rewrite = Some(Vec::new());
+        inner.path.with_extension("rewrite")
+    }
+
+    /// Adds the appends collected since [`AppendLog::start_rewrite`] to the new log at `tmp` and
+    /// puts it in place of the old one.
+    async fn finish_rewrite(&self, tmp: &Path, result: io::Result<File>) -> io::Result<u64> {
+        let mut inner = self.inner.lock().await;
+        let pending = inner.rewrite.take().unwrap_or_default();
+        let mut file = result?;
+
+        file.write_all(&pending).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(tmp, &inner.path).await?;
+
+        let size = file.metadata().await?.len();
+        inner.file = file;
+        inner.size = size;
+        inner.base_size = size;
+        Ok(size)
+    }
+
+    /// Writes the current state of `db` to a new log at `tmp`, left open for appending.
+    async fn rewrite(&self, db: &Database, tmp: &Path) -> io::Result<File> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(tmp)
+            .await?;
+
+        let mut buf = header();
+        for (key, data) in db.entries().await {
+            let value = data.inner().unwrap_or_default();
+            buf.extend(encode_set(&key, &value, data.remaining_ttl(), data.flags()));
+            if buf.len() >= 64 * 1024 {
+                file.write_all(&buf).await?;
+                buf.clear();
+            }
+        }
+        file.write_all(&buf).await?;
+        Ok(file)
+    }
+}
+
+fn header() -> Vec<u8> {
+    let mut buf = MAGIC.to_vec();
+    buf.extend_from_slice(&VERSION.to_be_bytes());
+    buf
+}
+
+fn encode_set(key: &str, value: &[u8], ttl: Option<Duration>, flags: u32) -> Vec<u8> {
+    // a ttl the clock can't represent never expires, as in the database
+    let expires_at = ttl
+        .and_then(|ttl| SystemTime::now().checked_add(ttl))
+        .map_or(-1, |at| {
+            at.duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as i64)
+        });
+
+    let mut record = vec![OP_SET];
+    push_bytes(&mut record, key.as_bytes());
+    record.extend_from_slice(&flags.to_be_bytes());
+    record.extend_from_slice(&expires_at.to_be_bytes());
+    push_bytes(&mut record, value);
+    record
+}
+
+fn push_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// Applies every complete record in `file` to `db`. Returns how many there were and where the
+/// last one ended, anything after that is a record the crash cut short.
+async fn replay(file: &mut File, db: &Database) -> io::Result<(usize, u64)> {
+    let mut input = BufReader::new(file);
+
+    let mut head = [0; MAGIC.len() + 2];
+    input.read_exact(&mut head).await?;
+    if head[..] != header()[..] {
+        return Err(invalid(
+            "not an rscache append-only log, or an unsupported version",
+        ));
+    }
+
+    let mut end = head.len() as u64;
+    let mut replayed = 0;
+    loop {
+        match read_record(&mut input, db).await {
+            Ok(Some(len)) => {
+                end += len;
+                replayed += 1;
+            }
+            Ok(None) => break,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                tracing::warn!(message = "Dropping a partial record from the append-only log");
+                break;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok((replayed, end))
+}
+
+/// Reads and applies one record, returning its length. `None` at the end of the log.
+async fn read_record(input: &mut BufReader<&mut File>, db: &Database) -> io::Result<Option<u64>> {
+    let op = match input.read_u8().await {
+        Ok(op) => op,
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if op == OP_FLUSH {
+        db.flush().await;
+        return Ok(Some(1));
+    }
+    let key = read_bytes(input).await?;
+    let mut len = 1 + 4 + key.len() as u64;
+    let key = String::from_utf8(key).map_err(|_| invalid("key is not UTF-8"))?;
+
+    match op {
+        OP_SET => {
+            let flags = input.read_u32().await?;
+            let expires_at = input.read_i64().await?;
+            let value = read_bytes(input).await?;
+            len += 4 + 8 + 4 + value.len() as u64;
+
+            let ttl = match u64::try_from(expires_at)
+                .ok()
+                .and_then(|at| until_unix(Duration::from_millis(at)))
+            {
+                Some(ttl) if ttl.is_zero() => {
+                    db.remove(&key).await;
+                    return Ok(Some(len));
+                }
+                ttl => ttl,
+            };
+            db.insert_replica(key, value, flags, ttl).await;
+        }
+        OP_DEL => {
+            db.remove(&key).await;
+        }
+        op => return Err(invalid(format!("unknown record type {}", op))),
+    }
+    Ok(Some(len))
+}
+
+async fn read_bytes(input: &mut BufReader<&mut File>) -> io::Result<Vec<u8>> {
+    let len = input.read_u32().await?;
+    if len as usize > MAX_VALUE_LEN {
+        return Err(invalid(format!("entry of {} bytes", len)));
+    }
+    let mut buf = vec![0; len as usize];
+    input.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every append has reached the file by the time it returns, so the tests can look at it.
+    const FSYNC: FsyncPolicy = FsyncPolicy::Always;
+
+    fn new_db() -> Database {
+        Database::new(Arc::new(serde_json::from_str("{}").expect("valid config")))
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rscache-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    async fn value(db: &Database, key: &str) -> Option<Vec<u8>> {
+        db.get_or_remove(key.to_string()).await?.inner()
+    }
+
+    #[tokio::test]
+    async fn changes_are_replayed_in_order() {
+        let path = temp_path("replay.aof");
+        let (log, replayed) = AppendLog::open(&path, FSYNC, &new_db()).await.unwrap();
+        assert_eq!(replayed, 0);
+        let hour = Some(Duration::from_secs(3600));
+        log.append_set("old", b"1", None, 0).await.unwrap();
+        log.append_flush().await.unwrap();
+        log.append_set("a", b"2", hour, 5).await.unwrap();
+        log.append_set("b", b"3", None, 0).await.unwrap();
+        log.append_del("b").await.unwrap();
+        log.append_set("expired", b"4", Some(Duration::ZERO), 0)
+            .await
+            .unwrap();
+        drop(log);
+
+        let db = new_db();
+        let (_, replayed) = AppendLog::open(&path, FSYNC, &db).await.unwrap();
+        assert_eq!(replayed, 6);
+        assert_eq!(db.stats().await.keys, 1);
+        let a = db.get_or_remove("a".into()).await.unwrap();
+        assert_eq!((a.inner().unwrap(), a.flags()), (b"2".to_vec(), 5));
+        assert!(a
+            .remaining_ttl()
+            .is_some_and(|ttl| ttl > Duration::from_secs(3590)));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_record_cut_short_is_dropped() {
+        let path = temp_path("truncated.aof");
+        let (log, _) = AppendLog::open(&path, FSYNC, &new_db()).await.unwrap();
+        log.append_set("a", b"1", None, 0).await.unwrap();
+        log.append_set("b", b"2", None, 0).await.unwrap();
+        drop(log);
+        let len = std::fs::metadata(&path).unwrap().len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        // the partial record is cut off, so what is appended next replays too
+        let db = new_db();
+        let (log, replayed) = AppendLog::open(&path, FSYNC, &db).await.unwrap();
+        assert_eq!(replayed, 1);
+        assert!(value(&db, "b").await.is_none());
+        log.append_set("c", b"3", None, 0).await.unwrap();
+        drop(log);
+
+        let db = new_db();
+        let (_, replayed) = AppendLog::open(&path, FSYNC, &db).await.unwrap();
+        assert_eq!(replayed, 2);
+        assert_eq!(value(&db, "c").await, Some(b"3".to_vec()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn compaction_keeps_appends_made_during_the_rewrite() {
+        let path = temp_path("compact.aof");
+        let db = new_db();
+        let (log, _) = AppendLog::open(&path, FSYNC, &db).await.unwrap();
+        for i in 0..100 {
+            log.append_set(&format!("k{}", i), b"v", None, 0)
+                .await
+                .unwrap();
+        }
+        log.append_flush().await.unwrap();
+        db.insert_replica("kept".into(), b"1".to_vec(), 0, None)
+            .await;
+        log.append_set("kept", b"1", None, 0).await.unwrap();
+        let before = std::fs::metadata(&path).unwrap().len();
+
+        // `compact` in steps, with an append that only the collected records hold
+        let tmp = log.start_rewrite().await;
+        log.append_set("late", b"2", None, 0).await.unwrap();
+        let result = log.rewrite(&db, &tmp).await;
+        assert!(log.finish_rewrite(&tmp, result).await.unwrap() < before);
+
+        let db = new_db();
+        let (_, replayed) = AppendLog::open(&path, FSYNC, &db).await.unwrap();
+        assert_eq!(replayed, 2);
+        assert_eq!(value(&db, "kept").await, Some(b"1".to_vec()));
+        assert_eq!(value(&db, "late").await, Some(b"2".to_vec()));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
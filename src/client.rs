@@ -1,25 +1,25 @@
 use core::fmt;
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
+use crate::{
+    config::Protocol,
+    database::StoreMode,
+    frame::{FrameError, FrameReader},
+    server::ServerMessages,
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
-    },
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
     sync::{mpsc::Sender, RwLock},
 };
-use tracing::{span, Level};
-
-use crate::server::ServerMessages;
 
 trait AsyncWritelnExt<S: ToString> {
     async fn writeln(&mut self, msg: S);
 }
 
-impl<S> AsyncWritelnExt<S> for TcpStream
+impl<S, W> AsyncWritelnExt<S> for W
 where
     S: ToString,
+    W: AsyncWrite + Unpin,
 {
     async fn writeln(&mut self, msg: S) {
         let msg = format!("{}\n", msg.to_string());
@@ -27,27 +27,69 @@ where
     }
 }
 
-impl<S> AsyncWritelnExt<S> for OwnedWriteHalf
-where
-    S: ToString,
-{
-    async fn writeln(&mut self, msg: S) {
-        let msg = format!("{}\n", msg.to_string());
-        let _ = self.write_all(msg.as_bytes()).await;
+/// A stream a client can be on: TCP, a unix socket, or TLS over TCP.
+pub trait Connection: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Sync + Unpin> Connection for S {}
+
+type ReadHalf = Box<dyn AsyncRead + Send + Sync + Unpin>;
+type WriteHalf = Box<dyn AsyncWrite + Send + Sync + Unpin>;
+
+/// Who is on the other end of a connection, what the server tells its clients apart by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    /// Peers on the unix socket have no address of their own, they are numbered as they connect
+    Unix(u64),
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix(n) => write!(f, "unix:{}", n),
+        }
+    }
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        PeerAddr::Tcp(addr)
     }
 }
 
-#[derive(Debug)]
 pub struct Client {
-    addr: SocketAddr,
+    addr: PeerAddr,
+    protocol: Protocol,
+    // negotiated with `HELLO 3` by redis clients
+    resp3: bool,
+    // presented a certificate signed by `tls_ca`, see `crate::tls`
+    verified: bool,
     state: Arc<RwLock<ClientState>>,
-    write: Arc<RwLock<OwnedWriteHalf>>,
-    read: Arc<RwLock<OwnedReadHalf>>,
+    write: Arc<RwLock<WriteHalf>>,
+    read: Arc<RwLock<ReadHalf>>,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("addr", &self.addr)
+            .field("protocol", &self.protocol)
+            .field("resp3", &self.resp3)
+            .field("verified", &self.verified)
+            .field("state", &self.state)
+            .finish_non_exhaustive()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ClientState {
-    SettingValue { key: String },
+    /// A `SET` without a value arrived, the next line is the value
+    SettingValue {
+        key: String,
+        dur: Duration,
+        mode: StoreMode,
+    },
     SettingKey,
 }
 
@@ -62,8 +104,8 @@ impl ClientState {
         *self = ClientState::SettingKey;
     }
 
-    fn setting_value(&mut self, key: String) {
-        *self = ClientState::SettingValue { key }
+    fn setting_value(&mut self, key: String, dur: Duration, mode: StoreMode) {
+        *self = ClientState::SettingValue { key, dur, mode }
     }
 }
 
@@ -74,15 +116,19 @@ impl fmt::Display for Client {
 }
 
 impl Client {
-    pub fn new(connection: TcpStream, addr: SocketAddr) -> Self {
-        let (read, write) = connection.into_split();
-        let read = Arc::new(RwLock::new(read));
-        let write = Arc::new(RwLock::new(write));
+    /// Takes over `connection`, a TCP or unix socket stream.
+    pub fn new<S: Connection + 'static>(connection: S, addr: PeerAddr, protocol: Protocol) -> Self {
+        let (read, write) = tokio::io::split(connection);
+        let read = Arc::new(RwLock::new(Box::new(read) as ReadHalf));
+        let write = Arc::new(RwLock::new(Box::new(write) as WriteHalf));
         let state = Arc::new(RwLock::new(ClientState::SettingKey));
         tracing::debug!("Created Client");
 
         Self {
             addr,
+            protocol,
+            resp3: false,
+            verified: false,
             state,
             write,
             read,
@@ -92,37 +138,38 @@ impl Client {
     pub async fn keep_open(&mut self, tx: Sender<ServerMessages>) {
         // Send a welcome message to the client;
         let read_h = Arc::clone(&self.read);
+        let write_h = Arc::clone(&self.write);
         let addr = self.addr;
+        let protocol = self.protocol;
 
         let _ = tokio::task::spawn(async move {
+            // Nobody else reads from the connection, so the reader keeps the lock for as long as
+            // the connection lives; its buffer has to survive between frames.
+            let mut read = read_h.write().await;
+            let mut frames = FrameReader::new(&mut *read, protocol);
+
             // Will read non stop
             loop {
-                let mut buf = [0u8; 64];
-
-                let read = read_h.write().await.read(&mut buf).await;
-
-                match read {
-                    Ok(0) => break,
-                    Ok(n) => {
-                        // Somerthing
-                        let msg = &buf[..n];
-                        let msg = String::from_utf8(msg.to_vec());
-                        let msg = match msg {
-                            Ok(m) => m,
-                            Err(err) => {
-                                tracing::error!(message = "Non-UTF8 string received", %err);
-                                break;
-                            }
-                        };
-
+                match frames.next_frame().await {
+                    Ok(Some(request)) => {
                         let _ = tx
-                            .send(ServerMessages::NewMessage(msg, addr))
+                            .send(ServerMessages::NewMessage(request, addr))
                             .await
                             .map_err(|err| {
                                 tracing::error!(message = "Could not send message to server -> NewMessage", %err);
                             });
                     }
-                    Err(_) => break,
+                    Ok(None) | Err(FrameError::Io(_)) => break,
+                    Err(err) => {
+                        // The stream can't be trusted to be on a frame boundary anymore
+                        tracing::error!(message = "Malformed frame, closing connection", %addr, %err);
+                        let reply = match frames.is_resp() {
+                            true => format!("-ERR Protocol error: {}\r", err),
+                            false => format!("ERROR {}", err),
+                        };
+                        write_h.write().await.writeln(reply).await;
+                        break;
+                    }
                 }
             }
             let _ = tx
@@ -136,6 +183,32 @@ impl Client {
         });
     }
 
+    pub fn addr(&self) -> PeerAddr {
+        self.addr
+    }
+
+    /// Marks the client as having presented a certificate signed by `tls_ca`.
+    pub fn with_verified_cert(mut self) -> Self {
+        self.verified = true;
+        self
+    }
+
+    pub fn verified(&self) -> bool {
+        self.verified
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn resp3(&self) -> bool {
+        self.resp3
+    }
+
+    pub fn set_resp3(&mut self, resp3: bool) {
+        self.resp3 = resp3;
+    }
+
     pub async fn disconnect(&self) {
         let _ = self.write.write().await.shutdown().await;
     }
@@ -144,10 +217,6 @@ impl Client {
         Arc::clone(&self.state).read().await.to_owned()
     }
 
-    pub async fn send_message(&mut self, msg: String) {
-        let write_h = Arc::clone(&self.write);
-        let _ = write_h.write().await.write(msg.as_bytes()).await;
-    }
     pub async fn send_messageln(&mut self, msg: String) {
         let write_h = Arc::clone(&self.write);
         write_h.write().await.writeln(msg).await;
@@ -155,13 +224,21 @@ impl Client {
 
     pub async fn send_messageb(&mut self, msg: &[u8]) {
         let write_h = Arc::clone(&self.write);
-        let _ = write_h.write().await.write(msg).await;
+        let _ = write_h.write().await.write_all(msg).await;
     }
 
     pub async fn change_state_to_settingkey(&mut self) {
         Arc::clone(&self.state).write().await.setting_key();
     }
-    pub async fn change_state_to_settingvalue(&mut self, key: String) {
-        Arc::clone(&self.state).write().await.setting_value(key)
+    pub async fn change_state_to_settingvalue(
+        &mut self,
+        key: String,
+        dur: Duration,
+        mode: StoreMode,
+    ) {
+        Arc::clone(&self.state)
+            .write()
+            .await
+            .setting_value(key, dur, mode)
     }
 }
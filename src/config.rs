@@ -1,79 +1,792 @@
 use std::fmt::{Display, Formatter};
+use std::io;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::Duration;
 
 use serde::Deserialize;
+use serde_json::{Map, Value};
 
-#[derive(Debug, Deserialize)]
+use crate::database::MAX_ENTRY_SIZE;
+
+/// The settings that can be overridden, with `RSCACHE_<NAME>` environment variables and with
+/// `--<name>` flags (dashes instead of underscores).
+const FIELDS: &[&str] = &[
+    "bind",
+    "port",
+    "unix_socket",
+    "unix_socket_perm",
+    "tls_cert",
+    "tls_key",
+    "tls_ca",
+    "parent",
+    "max_nodes",
+    "protocol",
+    "max_memory",
+    "eviction_policy",
+    "shards",
+    "snapshot_path",
+    "snapshot_interval",
+    "aof_path",
+    "aof_fsync",
+    "log_level",
+];
+
+/// The settings a reload applies to the running node, changes to any other need a restart.
+const LIVE: &[&str] = &["max_nodes", "max_memory", "eviction_policy", "log_level"];
+
+/// Settings that are still accepted but do nothing, a reload reports changes to them as such.
+const IGNORED: &[&str] = &["cleanup_time"];
+
+/// Read when neither `--config` nor `RSCACHE_CONFIG` name a file, skipped if it doesn't exist.
+const DEFAULT_PATH: &str = "config.json";
+
+pub const USAGE: &str = "\
+Usage: rscache [--config <path>] [--<setting> <value>]...
+
+Settings are read from the config file (config.json by default), then from RSCACHE_<SETTING>
+environment variables, then from the flags, later ones winning. Values are JSON, strings may be
+given without quotes.
+
+  --config <path>               config file to read, also RSCACHE_CONFIG
+  --bind <addrs>                addresses to listen on, [\"0.0.0.0\", \"::\"]
+  --port <port>                 0 lets the OS pick
+  --unix-socket <path>          also accept clients on a unix socket
+  --unix-socket-perm <mode>     octal permissions of the socket file, 660
+  --tls-cert <path>             PEM certificate chain, TLS needs all three
+  --tls-key <path>              PEM private key
+  --tls-ca <path>               PEM CA the other nodes' certificates are checked against
+  --parent <host:port>          node to join
+  --max-nodes <n>               children we accept before deferring joins
+  --protocol <native|memcached>
+  --max-memory <bytes>
+  --eviction-policy <lru|lfu|random|volatile-ttl|no-eviction>
+  --shards <n>
+  --snapshot-path <path>
+  --snapshot-interval <secs>
+  --aof-path <path>
+  --aof-fsync <always|everysec|no>
+  --log-level <trace|debug|info|warn|error>
+  -h, --help
+";
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    /// Addresses or hostnames to listen on, each optionally with its own port
+    bind: Option<Vec<String>>,
     port: Option<u16>,
+    /// Where to create a unix socket that clients can connect to as well
+    unix_socket: Option<PathBuf>,
+    /// Octal, like chmod takes it
+    unix_socket_perm: Option<String>,
+    /// With all three set the client port and node links speak TLS, see [`crate::tls`]
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    tls_ca: Option<PathBuf>,
     parent: Option<Node>,
-    cleanup_time: Option<u16>,
     max_nodes: Option<u16>,
+    protocol: Option<Protocol>,
+    /// Bytes the entries may take up before `eviction_policy` kicks in
+    max_memory: Option<usize>,
+    eviction_policy: Option<EvictionPolicy>,
+    /// How many independently locked parts the database is split into
+    shards: Option<usize>,
+    /// Where `SAVE` writes the database to, and where it's loaded from on startup
+    snapshot_path: Option<PathBuf>,
+    /// Seconds between automatic snapshots, none are taken without it
+    snapshot_interval: Option<u64>,
+    /// Where every write is logged, the database is restored from it instead of the snapshot
+    aof_path: Option<PathBuf>,
+    aof_fsync: Option<FsyncPolicy>,
+    log_level: Option<LogLevel>,
+    /// No longer used, expired entries are dropped in the background. Still accepted so older
+    /// config files keep loading
+    cleanup_time: Option<u16>,
+    /// The command line the config was loaded with, a reload applies it again
+    #[serde(skip)]
+    args: Vec<String>,
+}
+
+/// The language clients speak on the client port. Node links always use the native protocol.
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Native,
+    /// memcached's text protocol, so existing memcached client libraries work unchanged
+    Memcached,
+}
+
+/// Which entries make room for new ones once `max_memory` is reached, see [`crate::eviction`].
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EvictionPolicy {
+    /// Least recently used
+    #[default]
+    Lru,
+    /// Least frequently used
+    Lfu,
+    Random,
+    /// Closest to expiring, keys without a ttl are kept
+    VolatileTtl,
+    /// Reject writes instead
+    NoEviction,
+}
+
+/// When writes to the append-only log are flushed to disk, see [`crate::aof`].
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    /// Before the write is acknowledged, nothing acknowledged is ever lost
+    Always,
+    /// Once a second, a crash loses at most the last second
+    #[default]
+    EverySec,
+    /// Whenever the OS gets to it
+    No,
 }
 
-#[derive(Debug, Deserialize, Default, Clone)]
+/// The most detailed log lines that are still written.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl From<LogLevel> for tracing::Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Trace => tracing::Level::TRACE,
+            LogLevel::Debug => tracing::Level::DEBUG,
+            LogLevel::Info => tracing::Level::INFO,
+            LogLevel::Warn => tracing::Level::WARN,
+            LogLevel::Error => tracing::Level::ERROR,
+        }
+    }
+}
+
+/// Another node, by address or by a hostname looked up when connecting. Written as `host:port`
+/// (`[v6]:port` for IPv6 addresses), or the old `{"addr": [127, 0, 0, 1], "port": 6969}`.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(try_from = "NodeRepr")]
 pub struct Node {
-    addr: [u8; 4],
+    host: String,
     port: u16,
 }
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NodeRepr {
+    HostPort(String),
+    Ipv4 { addr: [u8; 4], port: u16 },
+}
+
+impl TryFrom<NodeRepr> for Node {
+    type Error = String;
+
+    fn try_from(repr: NodeRepr) -> Result<Self, Self::Error> {
+        match repr {
+            NodeRepr::HostPort(s) => s.parse(),
+            NodeRepr::Ipv4 { addr, port } => Ok(Node {
+                host: Ipv4Addr::from(addr).to_string(),
+                port,
+            }),
+        }
+    }
+}
+
+impl FromStr for Node {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("`{}` is not host:port", s))?;
+        let port = port
+            .parse()
+            .map_err(|_| format!("`{}` does not end in a port", s))?;
+        let host = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+            Some(v6) => v6,
+            None if host.contains(':') => {
+                return Err(format!("`{}` needs brackets around the IPv6 address", s))
+            }
+            None => host,
+        };
+        if host.is_empty() {
+            return Err(format!("`{}` has no host", s));
+        }
+        Ok(Node {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
 impl Display for Node {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}:{}",
-            self.addr
-                .to_vec()
-                .iter()
-                .map(|z| z.to_string())
-                .collect::<Vec<_>>()
-                .join(","),
-            self.port
-        )
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port),
+        }
     }
 }
 
 impl Node {
-    pub fn to_socketaddr(&self) -> SocketAddr {
-        SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::new(
-                self.addr[0],
-                self.addr[1],
-                self.addr[2],
-                self.addr[3],
+    /// One of the addresses to `bind`, `port` unless it names its own. An address or hostname,
+    /// optionally with a port: `0.0.0.0`, `::`, `localhost:7000`, `[::1]:7000`.
+    fn bind(entry: &str, port: u16) -> Result<Self, String> {
+        let bare = entry.trim_start_matches('[').trim_end_matches(']');
+        match bare.parse::<IpAddr>() {
+            Ok(ip) => Ok(Node {
+                host: ip.to_string(),
+                port,
+            }),
+            Err(_) if entry.contains(':') => entry.parse(),
+            Err(_) if entry.is_empty() => Err("empty address".to_string()),
+            Err(_) => Ok(Node {
+                host: entry.to_string(),
+                port,
+            }),
+        }
+    }
+
+    /// Every address the host goes by, looked up in DNS unless it's an address already.
+    pub async fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        let addrs = tokio::net::lookup_host((self.host.as_str(), self.port))
+            .await?
+            .collect::<Vec<_>>();
+        match addrs.is_empty() {
+            true => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no addresses", self.host),
             )),
-            self.port,
-        )
+            false => Ok(addrs),
+        }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
     }
 
-    pub fn addr(&self) -> [u8; 4] {
-        self.addr
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+/// Why the configuration could not be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// `--help` was asked for, not an error as such
+    Help,
+    /// The command line makes no sense, see [`USAGE`]
+    Usage(String),
+    Read {
+        path: PathBuf,
+        err: io::Error,
+    },
+    /// Every setting that failed to parse or doesn't add up, prefixed with where it came from
+    Invalid(Vec<String>),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Help => write!(f, "{}", USAGE.trim_end()),
+            ConfigError::Usage(msg) => write!(f, "{}\n\n{}", msg, USAGE.trim_end()),
+            ConfigError::Read { path, err } => {
+                write!(f, "could not read {}: {}", path.display(), err)
+            }
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n  {}", problem)?;
+                }
+                Ok(())
+            }
+        }
     }
 }
 
+impl std::error::Error for ConfigError {}
+
+/// The settings a reload found changed, by what became of the change.
+#[derive(Debug, Default)]
+pub struct Changes {
+    /// Now in effect
+    pub live: Vec<&'static str>,
+    /// Keep their old value until the node is restarted
+    pub restart: Vec<&'static str>,
+    /// Do nothing either way
+    pub ignored: Vec<&'static str>,
+}
+
 impl Config {
+    /// Reads the config file, then layers the `RSCACHE_*` environment variables and the command
+    /// line `args` (without the program name) on top of it.
+    pub fn load(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+        let all = args.into_iter().collect::<Vec<_>>();
+        let mut path = std::env::var_os("RSCACHE_CONFIG").map(PathBuf::from);
+        let mut flags = Vec::new();
+        let mut args = all.iter().cloned();
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                return Err(ConfigError::Help);
+            }
+            let Some(flag) = arg.strip_prefix("--") else {
+                return Err(ConfigError::Usage(format!("unexpected argument `{}`", arg)));
+            };
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (flag, None),
+            };
+            let field = name.replace('-', "_");
+            if field != "config" && !FIELDS.contains(&field.as_str()) {
+                return Err(ConfigError::Usage(format!("unknown flag --{}", name)));
+            }
+            let Some(value) = value.or_else(|| args.next()) else {
+                return Err(ConfigError::Usage(format!("--{} needs a value", name)));
+            };
+
+            match field.as_str() {
+                "config" => path = Some(PathBuf::from(value)),
+                _ => flags.push((format!("--{}", name), field, value)),
+            }
+        }
+
+        let mut settings = match path {
+            Some(path) => read(&path)?,
+            None if Path::new(DEFAULT_PATH).exists() => read(Path::new(DEFAULT_PATH))?,
+            None => Map::new(),
+        };
+
+        let env = FIELDS.iter().filter_map(|field| {
+            let var = format!("RSCACHE_{}", field.to_uppercase());
+            let value = std::env::var(&var).ok()?;
+            Some((var, field.to_string(), value))
+        });
+        let mut problems = Vec::new();
+        for (source, field, raw) in env.collect::<Vec<_>>().into_iter().chain(flags) {
+            match override_value(&field, &raw) {
+                Ok(value) => {
+                    settings.insert(field, value);
+                }
+                Err(err) => problems.push(format!("{}: {}", source, err)),
+            }
+        }
+        if !problems.is_empty() {
+            return Err(ConfigError::Invalid(problems));
+        }
+
+        let mut config = serde_json::from_value::<Config>(Value::Object(settings))
+            .map_err(|err| ConfigError::Invalid(vec![err.to_string()]))?;
+        config.validate()?;
+        if config.cleanup_time.is_some() {
+            tracing::warn!(message = "cleanup_time is deprecated and ignored");
+        }
+        config.args = all;
+        Ok(config)
+    }
+
+    /// Loads the configuration again, from the same file and with the same flags, and keeps
+    /// what can change while running. Returns the config to run with and the settings that
+    /// changed.
+    pub fn reload(&self) -> Result<(Config, Changes), ConfigError> {
+        let new = Config::load(self.args.clone())?;
+        let mut changes = Changes::default();
+        for field in self.changed(&new) {
+            match field {
+                _ if LIVE.contains(&field) => changes.live.push(field),
+                _ if IGNORED.contains(&field) => changes.ignored.push(field),
+                _ => changes.restart.push(field),
+            }
+        }
+
+        let config = Config {
+            max_nodes: new.max_nodes,
+            max_memory: new.max_memory,
+            eviction_policy: new.eviction_policy,
+            log_level: new.log_level,
+            cleanup_time: new.cleanup_time,
+            ..self.clone()
+        };
+        // the new max_memory is split between the shards still running
+        config.validate()?;
+        Ok((config, changes))
+    }
+
+    /// The settings that differ between the two.
+    fn changed(&self, other: &Config) -> Vec<&'static str> {
+        let fields = [
+            ("bind", self.bind != other.bind),
+            ("port", self.port != other.port),
+            ("unix_socket", self.unix_socket != other.unix_socket),
+            (
+                "unix_socket_perm",
+                self.unix_socket_perm != other.unix_socket_perm,
+            ),
+            ("tls_cert", self.tls_cert != other.tls_cert),
+            ("tls_key", self.tls_key != other.tls_key),
+            ("tls_ca", self.tls_ca != other.tls_ca),
+            ("parent", self.parent != other.parent),
+            ("max_nodes", self.max_nodes != other.max_nodes),
+            ("protocol", self.protocol != other.protocol),
+            ("max_memory", self.max_memory != other.max_memory),
+            (
+                "eviction_policy",
+                self.eviction_policy != other.eviction_policy,
+            ),
+            ("shards", self.shards != other.shards),
+            ("snapshot_path", self.snapshot_path != other.snapshot_path),
+            (
+                "snapshot_interval",
+                self.snapshot_interval != other.snapshot_interval,
+            ),
+            ("aof_path", self.aof_path != other.aof_path),
+            ("aof_fsync", self.aof_fsync != other.aof_fsync),
+            ("log_level", self.log_level != other.log_level),
+            ("cleanup_time", self.cleanup_time != other.cleanup_time),
+        ];
+        fields
+            .into_iter()
+            .filter_map(|(field, changed)| changed.then_some(field))
+            .collect()
+    }
+
+    /// Catches settings that parse fine but can't work, or have no effect, all at once.
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        if self.shards == Some(0) {
+            problems.push("shards: must be at least 1".to_string());
+        }
+        if self.max_memory == Some(0) {
+            problems.push("max_memory: must be above 0, leave it out for no limit".to_string());
+        } else if let Some(max) = self.max_memory {
+            // every shard evicts on its own, so each has to fit the largest entry by itself
+            let shards = self.shards();
+            if max.div_ceil(shards) < MAX_ENTRY_SIZE {
+                problems.push(format!(
+                    "max_memory: must be at least {} bytes with {} shards, {} per shard",
+                    MAX_ENTRY_SIZE * shards,
+                    shards,
+                    MAX_ENTRY_SIZE
+                ));
+            }
+        }
+        if self.snapshot_interval.is_some() && self.snapshot_path.is_none() {
+            problems.push("snapshot_interval: does nothing without snapshot_path".to_string());
+        }
+        if self.aof_fsync.is_some() && self.aof_path.is_none() {
+            problems.push("aof_fsync: does nothing without aof_path".to_string());
+        }
+        if self.aof_path.is_some() && self.aof_path == self.snapshot_path {
+            problems.push("aof_path: must not be the same file as snapshot_path".to_string());
+        }
+        if self.parent.as_ref().is_some_and(|p| p.port == 0) {
+            problems.push("parent: port must not be 0".to_string());
+        }
+        if self.bind.as_ref().is_some_and(Vec::is_empty) {
+            problems.push("bind: needs at least one address".to_string());
+        }
+        let tls = [&self.tls_cert, &self.tls_key, &self.tls_ca];
+        if tls.iter().any(|p| p.is_some()) && !tls.iter().all(|p| p.is_some()) {
+            problems.push("tls_cert, tls_key, tls_ca: TLS needs all three".to_string());
+        }
+        if let Some(ref perm) = self.unix_socket_perm {
+            if parse_mode(perm).is_none() {
+                problems.push(format!("unix_socket_perm: `{}` is not an octal mode", perm));
+            }
+            if self.unix_socket.is_none() {
+                problems.push("unix_socket_perm: does nothing without unix_socket".to_string());
+            }
+        }
+        for entry in self.bind.iter().flatten() {
+            if let Err(err) = Node::bind(entry, 0) {
+                problems.push(format!("bind: {}", err));
+            }
+        }
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(ConfigError::Invalid(problems)),
+        }
+    }
+
     pub fn max_nodes(&self) -> u16 {
         self.max_nodes.unwrap_or(3)
     }
 
-    /// Looks for `RSCACHE_PORT` in the environment if the port is not set in the config
+    /// What to listen on, `127.0.0.1` unless set. Entries without a port of their own get
+    /// [`Config::port`].
+    pub fn bind(&self) -> Vec<Node> {
+        let Some(ref bind) = self.bind else {
+            return vec![Node {
+                host: Ipv4Addr::LOCALHOST.to_string(),
+                port: self.port(),
+            }];
+        };
+        bind.iter()
+            .filter_map(|entry| Node::bind(entry, self.port()).ok())
+            .collect()
+    }
+
+    pub fn unix_socket(&self) -> Option<&Path> {
+        self.unix_socket.as_deref()
+    }
+
+    /// The mode the socket file is given, `None` leaves it to the umask
+    pub fn unix_socket_perm(&self) -> Option<u32> {
+        self.unix_socket_perm.as_deref().and_then(parse_mode)
+    }
+
+    pub fn tls_cert(&self) -> Option<&Path> {
+        self.tls_cert.as_deref()
+    }
+
+    pub fn tls_key(&self) -> Option<&Path> {
+        self.tls_key.as_deref()
+    }
+
+    pub fn tls_ca(&self) -> Option<&Path> {
+        self.tls_ca.as_deref()
+    }
+
+    /// Whether the client port and node links speak TLS, and nodes need a certificate to join.
+    pub fn tls(&self) -> bool {
+        self.tls_cert.is_some() && self.tls_key.is_some() && self.tls_ca.is_some()
+    }
+
     /// returns 0 if no port is set
     pub fn port(&self) -> u16 {
-        self.port.unwrap_or_else(|| {
-            std::env::var("RSCACHE_PORT")
-                .ok()
-                .and_then(|p| p.parse().ok())
-                .unwrap_or(0) // should just indicate to the OS to pick a port
-        })
+        self.port.unwrap_or(0) // should just indicate to the OS to pick a port
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol.unwrap_or_default()
+    }
+
+    pub fn max_memory(&self) -> Option<usize> {
+        self.max_memory
+    }
+
+    pub fn eviction_policy(&self) -> EvictionPolicy {
+        self.eviction_policy.unwrap_or_default()
+    }
+
+    pub fn shards(&self) -> usize {
+        self.shards.unwrap_or(16).max(1)
+    }
+
+    pub fn snapshot_path(&self) -> Option<&Path> {
+        self.snapshot_path.as_deref()
+    }
+
+    pub fn snapshot_interval(&self) -> Option<Duration> {
+        self.snapshot_interval
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    pub fn aof_path(&self) -> Option<&Path> {
+        self.aof_path.as_deref()
+    }
+
+    pub fn aof_fsync(&self) -> FsyncPolicy {
+        self.aof_fsync.unwrap_or_default()
+    }
+
+    /// `None` leaves it at the default the binary was built with
+    pub fn log_level(&self) -> Option<LogLevel> {
+        self.log_level
     }
 
     pub fn parent(&self) -> Option<Node> {
         self.parent.clone()
     }
+}
+
+/// The settings in the file at `path`. Parsed into a [`Config`] first, so mistakes in the file
+/// are reported with their line and column.
+fn read(path: &Path) -> Result<Map<String, Value>, ConfigError> {
+    let text = std::fs::read_to_string(path).map_err(|err| ConfigError::Read {
+        path: path.to_path_buf(),
+        err,
+    })?;
+    let invalid =
+        |err: serde_json::Error| ConfigError::Invalid(vec![format!("{}: {}", path.display(), err)]);
+    serde_json::from_str::<Config>(&text).map_err(invalid)?;
+    serde_json::from_str(&text).map_err(invalid)
+}
+
+/// The value an environment variable or flag sets `field` to. Taken as JSON, and failing that as
+/// a plain string so paths and names need no quotes.
+fn override_value(field: &str, raw: &str) -> Result<Value, serde_json::Error> {
+    let check = |value: &Value| {
+        let single = Map::from_iter([(field.to_string(), value.clone())]);
+        serde_json::from_value::<Config>(Value::Object(single)).map(|_| ())
+    };
+    let string = Value::String(raw.to_string());
+    match serde_json::from_str::<Value>(raw) {
+        Ok(value) => match check(&value) {
+            Ok(()) => Ok(value),
+            Err(err) => check(&string).map(|_| string).map_err(|_| err),
+        },
+        Err(_) => check(&string).map(|_| string),
+    }
+}
+
+/// An octal file mode like `660` or `0660`, permission bits only.
+fn parse_mode(mode: &str) -> Option<u32> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Held by the tests that call [`Config::load`], the environment is shared by all of them.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// A config file with `json` in it, named after the test.
+    fn config_file(name: &str, json: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rscache-{}-{}", std::process::id(), name));
+        std::fs::write(&path, json).unwrap();
+        path
+    }
+
+    fn load(path: &Path, flags: &[&str]) -> Result<Config, ConfigError> {
+        let args = ["--config", path.to_str().unwrap()]
+            .into_iter()
+            .chain(flags.iter().copied());
+        Config::load(args.map(str::to_string))
+    }
+
+    fn config(json: &str) -> Config {
+        serde_json::from_str(json).expect("valid config")
+    }
+
+    fn problems(config: &Config) -> Vec<String> {
+        match config.validate() {
+            Ok(()) => Vec::new(),
+            Err(ConfigError::Invalid(problems)) => problems,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    #[test]
+    fn every_shard_fits_the_largest_entry() {
+        let fits = MAX_ENTRY_SIZE * 16;
+        assert!(problems(&config(&format!(r#"{{ "max_memory": {} }}"#, fits))).is_empty());
+        let small = format!(r#"{{ "max_memory": {} }}"#, fits - 16);
+        assert_eq!(problems(&config(&small)).len(), 1);
+        assert!(problems(&config(&small))[0].starts_with("max_memory:"));
+
+        let one_shard = format!(r#"{{ "max_memory": {}, "shards": 1 }}"#, MAX_ENTRY_SIZE);
+        assert!(problems(&config(&one_shard)).is_empty());
+    }
+
+    #[test]
+    fn flags_win_over_the_environment_which_wins_over_the_file() {
+        let _env = ENV.lock().unwrap();
+        let path = config_file(
+            "layers.json",
+            r#"{ "max_nodes": 1, "port": 7000, "shards": 2 }"#,
+        );
+        std::env::set_var("RSCACHE_MAX_NODES", "2");
+        std::env::set_var("RSCACHE_PORT", "7001");
+        let config = load(&path, &["--port=7002", "--eviction-policy", "lfu"]);
+        std::env::remove_var("RSCACHE_MAX_NODES");
+        std::env::remove_var("RSCACHE_PORT");
+
+        let config = config.unwrap();
+        assert_eq!(config.shards(), 2);
+        assert_eq!(config.max_nodes(), 2);
+        assert_eq!(config.port(), 7002);
+        assert_eq!(config.eviction_policy(), EvictionPolicy::Lfu);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let _env = ENV.lock().unwrap();
+        let path = config_file("invalid.json", r#"{ "shards": 0, "aof_fsync": "always" }"#);
+        match load(&path, &["--unix-socket-perm", "999"]) {
+            Err(ConfigError::Invalid(problems)) => {
+                assert_eq!(problems.len(), 4, "{:?}", problems);
+                assert!(problems[0].starts_with("shards:"));
+            }
+            other => panic!("{:?}", other),
+        }
+
+        // values that don't parse are reported with where they came from
+        std::env::set_var("RSCACHE_MAX_NODES", "lots");
+        let res = load(&path, &["--eviction-policy", "fifo"]);
+        std::env::remove_var("RSCACHE_MAX_NODES");
+        match res {
+            Err(ConfigError::Invalid(problems)) => {
+                assert_eq!(problems.len(), 2, "{:?}", problems);
+                assert!(problems[0].starts_with("RSCACHE_MAX_NODES:"));
+                assert!(problems[1].starts_with("--eviction-policy:"));
+            }
+            other => panic!("{:?}", other),
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bad_files_and_flags() {
+        let _env = ENV.lock().unwrap();
+        let path = config_file("typo.json", "{ \"max_nodes\": 1,\n  \"max_node\": 2 }");
+        match load(&path, &[]) {
+            Err(ConfigError::Invalid(problems)) => {
+                assert!(problems[0].contains("max_node"));
+                assert!(problems[0].contains("line 2"));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(load(&path, &["--help"]), Err(ConfigError::Help)));
+        assert!(matches!(
+            load(&path, &["--nope", "1"]),
+            Err(ConfigError::Usage(_))
+        ));
+        assert!(matches!(
+            load(&path, &["--port"]),
+            Err(ConfigError::Usage(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+
+        let missing = std::env::temp_dir().join("rscache-missing.json");
+        assert!(matches!(load(&missing, &[]), Err(ConfigError::Read { .. })));
+    }
+
+    #[test]
+    fn reloads_apply_what_they_can() {
+        let _env = ENV.lock().unwrap();
+        let path = config_file("reload.json", r#"{ "max_nodes": 1, "port": 7000 }"#);
+        let config = load(&path, &["--shards", "4"]).unwrap();
+        let json = r#"{ "max_nodes": 2, "port": 7001, "shards": 8, "cleanup_time": 5 }"#;
+        std::fs::write(&path, json).unwrap();
+
+        let (reloaded, changes) = config.reload().unwrap();
+        assert_eq!(changes.live, ["max_nodes"]);
+        assert_eq!(changes.restart, ["port"]);
+        assert_eq!(changes.ignored, ["cleanup_time"]);
+        assert_eq!(reloaded.max_nodes(), 2);
+        assert_eq!(reloaded.port(), 7000);
+        // the flag still wins over the file
+        assert_eq!(reloaded.shards(), 4);
 
-    pub fn cleanup_time_as_duration(&self) -> Duration {
-        let ct = self.cleanup_time.unwrap_or(60);
-        Duration::from_secs(ct.into())
+        std::fs::write(&path, r#"{ "max_nodes": 0, "port": "x" }"#).unwrap();
+        assert!(matches!(config.reload(), Err(ConfigError::Invalid(_))));
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -1,26 +1,316 @@
 use std::{
-    collections::HashMap,
-    time::{Duration, Instant},
+    cmp::Reverse,
+    collections::{BTreeSet, BinaryHeap, HashMap},
+    hash::{BuildHasher, RandomState},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, RwLock as StdRwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use tokio::time::interval;
+use tokio::time::{interval, Instant};
 
-use crate::config::Config;
+use crate::{
+    config::{Config, EvictionPolicy},
+    eviction::{self, Eviction},
+    frame::{MAX_LINE_LEN, MAX_VALUE_LEN},
+};
 
-#[derive(Debug)]
+/// The entries, split into shards by the hash of their key. Every shard has its own lock, so
+/// operations on different keys rarely wait on each other. Clones share the same entries.
+#[derive(Debug, Clone)]
 pub struct Database {
-    inner: Arc<RwLock<HashMap<String, Data>>>,
-    config: Arc<Config>,
+    shards: Arc<Vec<RwLock<Table>>>,
+    hasher: RandomState,
+    // swapped by `reconfigure`
+    config: Arc<StdRwLock<Arc<Config>>>,
+    // last CAS token handed out
+    cas: Arc<AtomicU64>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Data {
-    inner: Option<String>,
-    // time to live in seconds
-    ttl: Duration,
-    time_added: tokio::time::Instant,
+    inner: Option<Vec<u8>>,
+    // time to live, `None` never expires
+    ttl: Option<Duration>,
+    time_added: Instant,
+    // opaque to us, memcached clients use them to tag how the value was serialized
+    flags: u32,
+    // changes on every write to the entry, see `StoreMode::Cas`
+    cas: u64,
+}
+
+/// Memory accounting, see [`Database::stats`].
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub keys: usize,
+    pub used_memory: usize,
+    pub max_memory: Option<usize>,
+    pub evictions: u64,
+}
+
+impl Stats {
+    /// `(name, value)` pairs in the order the `STATS` commands list them, an unlimited
+    /// `max_memory` is 0.
+    pub fn fields(&self) -> [(&'static str, u64); 4] {
+        [
+            ("keys", self.keys as u64),
+            ("used_memory", self.used_memory as u64),
+            ("max_memory", self.max_memory.unwrap_or(0) as u64),
+            ("evictions", self.evictions),
+        ]
+    }
+}
+
+/// A write that didn't fit under `max_memory`, and the eviction policy had nothing to evict. A
+/// value longer than [`MAX_VALUE_LEN`] is refused the same way, snapshots and the append-only
+/// log couldn't read it back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfMemory;
+
+/// What the largest entry counts as against `max_memory`: the longest key a command line leaves
+/// room for and the largest value. Every shard's part of `max_memory` has to hold one.
+pub const MAX_ENTRY_SIZE: usize = MAX_LINE_LEN + MAX_VALUE_LEN + std::mem::size_of::<Data>();
+
+/// Longest ttl a write may ask for, about a hundred years. Longer ones are refused as invalid
+/// expire times instead of overflowing the clock.
+pub const MAX_TTL: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// The ttl that expires `since_epoch` after the unix epoch, zero if that has passed already.
+/// `None` if it's too far out for the clock to represent.
+pub fn until_unix(since_epoch: Duration) -> Option<Duration> {
+    let at = UNIX_EPOCH.checked_add(since_epoch)?;
+    Some(
+        at.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// How often expired entries are looked for.
+const EXPIRE_TICK: Duration = Duration::from_millis(100);
+/// Most deadlines a shard goes through per tick, stale ones included, so a mass expiry can't hold
+/// its lock for long. Whatever is left is picked up on the following ticks.
+const MAX_EXPIRED_PER_TICK: usize = 512;
+
+/// One shard of the entries and what it takes to keep them under its part of `max_memory`: how
+/// many bytes they take up and the eviction policy, which picks the entries that make room for
+/// new ones. Without a `max_memory` there is no policy to keep informed.
+#[derive(Debug)]
+struct Table {
+    entries: HashMap<String, Data>,
+    // behind its own lock so reads can count as uses while holding only a read lock on the table
+    policy: Option<Mutex<Box<dyn Eviction>>>,
+    // deadlines of the entries with a ttl, soonest first. Rewriting or removing an entry leaves
+    // its old deadline behind, those are skipped when they come up and dropped all at once when
+    // they outnumber the entries.
+    expiry: BinaryHeap<Reverse<(Instant, String)>>,
+    used: usize,
+    max_memory: Option<usize>,
+    evictions: u64,
+}
+
+impl Table {
+    fn new(max_memory: Option<usize>, policy: EvictionPolicy) -> Self {
+        Self {
+            entries: HashMap::new(),
+            policy: max_memory.map(|_| Mutex::new(eviction::policy(policy))),
+            expiry: BinaryHeap::new(),
+            used: 0,
+            max_memory,
+            evictions: 0,
+        }
+    }
+
+    /// Applies a new share of `max_memory`, and a new policy that starts out knowing every key
+    /// if there was none or `rebuild` asks for it. Lowering the limit doesn't evict anything
+    /// right away, the next write makes room.
+    fn set_limit(&mut self, max_memory: Option<usize>, policy: EvictionPolicy, rebuild: bool) {
+        self.max_memory = max_memory;
+        if max_memory.is_none() {
+            self.policy = None;
+        } else if self.policy.is_none() || rebuild {
+            let mut policy = eviction::policy(policy);
+            for key in self.entries.keys() {
+                policy.insert(key);
+            }
+            self.policy = Some(Mutex::new(policy));
+        }
+    }
+
+    fn policy(&mut self) -> Option<&mut Box<dyn Eviction>> {
+        self.policy
+            .as_mut()
+            .map(|p| p.get_mut().expect("eviction policy poisoned"))
+    }
+
+    /// Looks a live entry up and counts it as used.
+    fn get(&self, key: &str) -> Option<&Data> {
+        let data = self.entries.get(key).filter(|v| v.validate_cache())?;
+        if let Some(ref policy) = self.policy {
+            policy.lock().expect("eviction policy poisoned").touch(key);
+        }
+        Some(data)
+    }
+
+    /// Looks the entry up and counts it as used. Changing the value through it would throw off
+    /// the accounting, that goes through [`Table::insert`].
+    fn get_mut(&mut self, key: &str) -> Option<&mut Data> {
+        let data = self.entries.get_mut(key)?;
+        // a missing key mustn't make it into the policy, nothing would ever take it out again
+        if let Some(ref mut policy) = self.policy {
+            policy
+                .get_mut()
+                .expect("eviction policy poisoned")
+                .touch(key);
+        }
+        Some(data)
+    }
+
+    /// Inserts or replaces the entry, first evicting what the policy picks until it fits. An entry
+    /// bigger than the whole table is refused right away, if it can't be made to fit otherwise
+    /// the table is left as it was, minus whatever was evicted.
+    fn insert(&mut self, key: String, data: Data) -> Result<(), OutOfMemory> {
+        if data.inner.as_ref().is_some_and(|v| v.len() > MAX_VALUE_LEN) {
+            return Err(OutOfMemory);
+        }
+        let size = data.size(&key);
+        let replaced = self.entries.get(&key).map_or(0, |v| v.size(&key));
+        if let Some(max) = self.max_memory {
+            // evicting everything wouldn't make room either, so don't start
+            if size > max {
+                return Err(OutOfMemory);
+            }
+            while self.used - replaced + size > max {
+                let policy = self
+                    .policy
+                    .as_mut()
+                    .expect("max_memory comes with a policy");
+                let victim = policy
+                    .get_mut()
+                    .expect("eviction policy poisoned")
+                    .victim(&self.entries, &key)
+                    .ok_or(OutOfMemory)?;
+                // a key the policy still knows but the table doesn't is dropped, not counted
+                if self.remove(&victim).is_none() {
+                    tracing::warn!(
+                        message = "Eviction policy picked a missing key",
+                        key = victim
+                    );
+                    if let Some(policy) = self.policy() {
+                        policy.remove(&victim);
+                    }
+                    continue;
+                }
+                tracing::debug!(message = "Evicting", key = victim);
+                self.evictions += 1;
+            }
+        }
+
+        self.used = self.used - replaced + size;
+        if let Some(policy) = self.policy() {
+            policy.insert(&key);
+        }
+        self.entries.insert(key.clone(), data);
+        self.schedule(&key);
+        Ok(())
+    }
+
+    /// Queues the entry's deadline, needed whenever its ttl or `time_added` changed.
+    fn schedule(&mut self, key: &str) {
+        let Some(deadline) = self.entries.get(key).and_then(Data::deadline) else {
+            return;
+        };
+        self.expiry.push(Reverse((deadline, key.to_string())));
+        // an entry whose ttl keeps being pushed back leaves a deadline behind every time
+        if self.expiry.len() > 2 * self.entries.len() + 64 {
+            self.expiry = self
+                .entries
+                .iter()
+                .filter_map(|(k, v)| Some(Reverse((v.deadline()?, k.clone()))))
+                .collect();
+        }
+    }
+
+    /// Looks at up to `limit` deadlines that have passed, dropping the entries they still belong
+    /// to.
+    fn expire(&mut self, limit: usize) {
+        let now = Instant::now();
+        for _ in 0..limit {
+            match self.expiry.peek() {
+                Some(Reverse((deadline, _))) if *deadline <= now => {}
+                _ => break,
+            }
+            let Reverse((deadline, key)) = self.expiry.pop().expect("peeked above");
+            // the entry may have been removed or given a later deadline since
+            if self.entries.get(&key).and_then(Data::deadline) == Some(deadline) {
+                self.remove(&key);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Data> {
+        let data = self.entries.remove(key)?;
+        if let Some(policy) = self.policy() {
+            policy.remove(key);
+        }
+        self.used -= data.size(key);
+        Some(data)
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.expiry.clear();
+        if let Some(policy) = self.policy() {
+            policy.clear();
+        }
+        self.used = 0;
+    }
+}
+
+/// How [`Database::store`] treats a key that already holds a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreMode {
+    /// Always write
+    Set,
+    /// Only write if the key holds no value
+    Add,
+    /// Only write if the key holds a value
+    Replace,
+    /// Add to the end of the existing value, flags and ttl are kept
+    Append,
+    /// Add to the front of the existing value, flags and ttl are kept
+    Prepend,
+    /// Only write if the entry was not modified since the token was read
+    Cas(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreResult {
+    Stored,
+    NotStored,
+    /// `StoreMode::Cas` token did not match
+    Exists,
+    /// `StoreMode::Cas` on a key without a value
+    NotFound,
+    OutOfMemory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterError {
+    NotFound,
+    NotANumber,
+    /// The result does not fit a signed 64 bit integer
+    Overflow,
+    OutOfMemory,
+}
+
+impl From<OutOfMemory> for CounterError {
+    fn from(_: OutOfMemory) -> Self {
+        CounterError::OutOfMemory
+    }
 }
 
 impl Default for Data {
@@ -32,82 +322,736 @@ impl Default for Data {
 impl Data {
     pub fn new() -> Self {
         Self {
-            time_added: tokio::time::Instant::now(),
+            time_added: Instant::now(),
             ..Default::default()
         }
     }
 
-    pub fn inner(&self) -> Option<String> {
+    fn with_value(value: Vec<u8>, flags: u32, ttl: Option<Duration>, cas: u64) -> Self {
+        Self {
+            inner: Some(value),
+            ttl,
+            time_added: Instant::now(),
+            flags,
+            cas,
+        }
+    }
+
+    /// What the entry counts as against `max_memory`: the key, the value and the bookkeeping.
+    fn size(&self, key: &str) -> usize {
+        key.len() + self.inner.as_ref().map_or(0, Vec::len) + std::mem::size_of::<Self>()
+    }
+
+    pub fn inner(&self) -> Option<Vec<u8>> {
         self.inner.to_owned()
     }
 
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    pub fn cas(&self) -> u64 {
+        self.cas
+    }
+
+    /// How long until the entry expires, zero if it already has. `None` if it never expires.
+    pub fn remaining_ttl(&self) -> Option<Duration> {
+        let elapsed = Instant::now().saturating_duration_since(self.time_added);
+        self.ttl.map(|ttl| ttl.saturating_sub(elapsed))
+    }
+
+    /// When the entry expires, `None` if it never does. A ttl too long for the clock to
+    /// represent never expires either.
+    fn deadline(&self) -> Option<Instant> {
+        self.time_added.checked_add(self.ttl?)
+    }
+
     pub fn validate_cache(&self) -> bool {
-        let now = Instant::now();
-        now.saturating_duration_since(self.time_added.into())
-            .as_secs()
-            < self.ttl.as_secs()
+        let Some(ttl) = self.ttl else {
+            return true;
+        };
+        Instant::now().saturating_duration_since(self.time_added) < ttl
+    }
+
+    fn has_value(&self) -> bool {
+        self.inner.is_some() && self.validate_cache()
     }
 }
 
 impl Database {
     pub fn new(config: Arc<Config>) -> Self {
+        let shards = config.shards();
+        // the memory budget is split evenly, every shard evicts on its own
+        let max_memory = config.max_memory().map(|max| max.div_ceil(shards));
+        let shards = (0..shards)
+            .map(|_| RwLock::new(Table::new(max_memory, config.eviction_policy())))
+            .collect();
         Self {
-            inner: Arc::new(RwLock::new(HashMap::new())),
-            config,
+            shards: Arc::new(shards),
+            hasher: RandomState::new(),
+            config: Arc::new(StdRwLock::new(config)),
+            cas: Arc::new(AtomicU64::new(0)),
         }
     }
 
-    pub async fn keep_valid(&mut self) {
-        let inner = Arc::clone(&self.inner);
-        let dur = self.config.as_ref().cleanup_time_as_duration();
+    fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.config.read().expect("config lock poisoned"))
+    }
+
+    /// Switches to `config`, applying its `max_memory` and `eviction_policy`. The number of
+    /// shards stays what it was.
+    pub async fn reconfigure(&self, config: Arc<Config>) {
+        let old = std::mem::replace(
+            &mut *self.config.write().expect("config lock poisoned"),
+            Arc::clone(&config),
+        );
+        let max_memory = config
+            .max_memory()
+            .map(|max| max.div_ceil(self.shards.len()));
+        let rebuild = old.eviction_policy() != config.eviction_policy();
+        for shard in self.shards.iter() {
+            shard
+                .write()
+                .await
+                .set_limit(max_memory, config.eviction_policy(), rebuild);
+        }
+    }
+
+    fn next_cas(&self) -> u64 {
+        self.cas.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn shard_index(&self, key: &str) -> usize {
+        (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+    }
+
+    /// The shard `key` lives in.
+    fn shard(&self, key: &str) -> &RwLock<Table> {
+        &self.shards[self.shard_index(key)]
+    }
+
+    /// The shards holding `keys`, each once and in ascending order - the order every multi-shard
+    /// operation locks them in, so two of them can't deadlock.
+    fn shards_of<'a>(&self, keys: impl Iterator<Item = &'a str>) -> BTreeSet<usize> {
+        keys.map(|k| self.shard_index(k)).collect()
+    }
+
+    /// Drops expired entries in the background, every [`EXPIRE_TICK`] at most
+    /// [`MAX_EXPIRED_PER_TICK`] per shard.
+    pub async fn keep_valid(&self) {
+        let shards = Arc::clone(&self.shards);
         tokio::task::spawn(async move {
-            let mut interval = interval(dur);
+            let mut interval = interval(EXPIRE_TICK);
             loop {
                 interval.tick().await;
-                inner.write().await.retain(|_, v| v.validate_cache());
+                // one shard at a time, so the others stay available
+                for shard in shards.iter() {
+                    shard.write().await.expire(MAX_EXPIRED_PER_TICK);
+                }
             }
         });
     }
 
-    pub async fn insert_key(&mut self, key: String, ttl: Duration) {
-        let data = Data {
-            inner: None,
-            ttl,
-            time_added: tokio::time::Instant::now(),
-        };
-        let table = Arc::clone(&self.inner);
-        table.write().await.insert(key, data);
+    /// Writes a value from the native protocol, which has no flags. `mode` decides whether an
+    /// existing value gets overwritten, see [`StoreMode`].
+    pub async fn insert_key_value(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        ttl: Duration,
+        mode: StoreMode,
+    ) -> StoreResult {
+        self.store(key, value, 0, Some(ttl), mode).await
     }
 
-    pub async fn insert_key_value(&mut self, key: String, value: String) {
-        let table = Arc::clone(&self.inner);
-        let mut table = table.write().await;
+    /// Writes `value` according to `mode`, checking and writing under a single lock so
+    /// concurrent writers can't interleave. Expired entries count as absent.
+    pub async fn store(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        flags: u32,
+        ttl: Option<Duration>,
+        mode: StoreMode,
+    ) -> StoreResult {
+        let cas = self.next_cas();
+        let mut table = self.shard(&key).write().await;
+        let current = table.get_mut(&key).filter(|v| v.has_value());
 
-        match table.get_mut(&key) {
-            Some(ref mut v) => {
-                if v.inner.is_none() {
-                    let _ = v.inner.insert(value);
-                }
+        match (mode, current) {
+            (StoreMode::Add, Some(_))
+            | (StoreMode::Replace | StoreMode::Append | StoreMode::Prepend, None) => {
+                return StoreResult::NotStored
+            }
+            (StoreMode::Cas(_), None) => return StoreResult::NotFound,
+            (StoreMode::Cas(token), Some(v)) if v.cas != token => return StoreResult::Exists,
+            (StoreMode::Append, Some(v)) => {
+                let mut v = v.clone();
+                v.inner.get_or_insert_with(Vec::new).extend(value);
+                v.cas = cas;
+                table.insert(key, v)
+            }
+            (StoreMode::Prepend, Some(v)) => {
+                let mut v = v.clone();
+                v.inner.get_or_insert_with(Vec::new).splice(0..0, value);
+                v.cas = cas;
+                table.insert(key, v)
+            }
+            (StoreMode::Set | StoreMode::Add | StoreMode::Replace | StoreMode::Cas(_), _) => {
+                table.insert(key, Data::with_value(value, flags, ttl, cas))
             }
-            None => {
-                let data = Data {
-                    inner: Some(value),
-                    ttl: Duration::from_secs(10),
-                    time_added: tokio::time::Instant::now(),
-                };
-                table.insert(key, data);
+        }
+        .map_or(StoreResult::OutOfMemory, |_| StoreResult::Stored)
+    }
+
+    /// Overwrites every key with its value, holding the locks of all the shards involved at once
+    /// so no one sees half of the batch. Either every key is written or, if they don't all fit
+    /// under `max_memory`, none is - though entries evicted on the way stay evicted.
+    pub async fn store_many(
+        &self,
+        pairs: Vec<(String, Vec<u8>)>,
+        ttl: Option<Duration>,
+    ) -> Result<(), OutOfMemory> {
+        let mut tables = HashMap::new();
+        for idx in self.shards_of(pairs.iter().map(|(k, _)| k.as_str())) {
+            tables.insert(idx, self.shards[idx].write().await);
+        }
+
+        // what the keys held before, to put back if the batch fails
+        let mut previous = Vec::with_capacity(pairs.len());
+        let mut fits = true;
+        for (key, value) in pairs {
+            let data = Data::with_value(value, 0, ttl, self.next_cas());
+            let table = tables
+                .get_mut(&self.shard_index(&key))
+                .expect("locked above");
+            previous.push((key.clone(), table.entries.get(&key).cloned()));
+            if table.insert(key, data).is_err() {
+                fits = false;
+                break;
+            }
+        }
+        // a later key may have evicted an earlier one to make room
+        fits &= previous
+            .iter()
+            .all(|(key, _)| tables[&self.shard_index(key)].entries.contains_key(key));
+        if fits {
+            return Ok(());
+        }
+
+        // newest first, so a key given twice gets back what it held before the batch
+        for (key, old) in previous.into_iter().rev() {
+            let table = tables
+                .get_mut(&self.shard_index(&key))
+                .expect("locked above");
+            table.remove(&key);
+            if let Some(old) = old {
+                if table.insert(key.clone(), old).is_err() {
+                    tracing::warn!(message = "Out of memory, dropping key", key);
+                }
             }
         }
+        Err(OutOfMemory)
+    }
+
+    /// Stores a value received from another node, replacing whatever we had for the key - the
+    /// sending node already decided which write wins.
+    pub async fn insert_replica(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        flags: u32,
+        ttl: Option<Duration>,
+    ) {
+        let data = Data::with_value(value, flags, ttl, self.next_cas());
+        let mut table = self.shard(&key).write().await;
+        if table.insert(key.clone(), data).is_err() {
+            tracing::warn!(message = "Out of memory, dropping replicated key", key);
+        }
     }
-    pub async fn get_or_remove(&mut self, k: String) -> Option<Data> {
-        let table = Arc::clone(&self.inner);
-        let x = table.write().await.get(&k)?.clone();
 
-        if x.validate_cache() {
-            return Some(x);
+    /// Removes the key, returns whether it held a live value.
+    pub async fn remove(&self, key: &str) -> bool {
+        let removed = self.shard(key).write().await.remove(key);
+        removed.is_some_and(|v| v.has_value())
+    }
+
+    /// Adds to (or with `decr` subtracts from) a value holding an unsigned decimal number, the
+    /// memcached way: increments wrap around at 2^64 and decrements stop at 0. Returns the new
+    /// value.
+    pub async fn incr(&self, key: &str, delta: u64, decr: bool) -> Result<u64, CounterError> {
+        let cas = self.next_cas();
+        let mut table = self.shard(key).write().await;
+        let v = table
+            .get_mut(key)
+            .filter(|v| v.has_value())
+            .ok_or(CounterError::NotFound)?;
+
+        let current = v
+            .inner
+            .as_deref()
+            .and_then(|n| std::str::from_utf8(n).ok())
+            .and_then(|n| n.trim().parse::<u64>().ok())
+            .ok_or(CounterError::NotANumber)?;
+        let new = if decr {
+            current.saturating_sub(delta)
+        } else {
+            current.wrapping_add(delta)
+        };
+
+        let mut v = v.clone();
+        v.inner = Some(new.to_string().into_bytes());
+        v.cas = cas;
+        table.insert(key.to_string(), v)?;
+        Ok(new)
+    }
+
+    /// Adds `delta` to a value holding a signed decimal number and returns the result, all under
+    /// the write lock. A key without a value starts out as `init` and expires after `ttl`, an
+    /// existing counter keeps its ttl.
+    pub async fn counter(
+        &self,
+        key: String,
+        delta: i64,
+        init: i64,
+        ttl: Option<Duration>,
+    ) -> Result<i64, CounterError> {
+        let cas = self.next_cas();
+        let mut table = self.shard(&key).write().await;
+
+        let Some(v) = table.get_mut(&key).filter(|v| v.has_value()) else {
+            let new = init.checked_add(delta).ok_or(CounterError::Overflow)?;
+            let data = Data::with_value(new.to_string().into_bytes(), 0, ttl, cas);
+            table.insert(key, data)?;
+            return Ok(new);
+        };
+
+        let current = v
+            .inner
+            .as_deref()
+            .and_then(|n| std::str::from_utf8(n).ok())
+            .and_then(|n| n.trim().parse::<i64>().ok())
+            .ok_or(CounterError::NotANumber)?;
+        let new = current.checked_add(delta).ok_or(CounterError::Overflow)?;
+
+        let mut v = v.clone();
+        v.inner = Some(new.to_string().into_bytes());
+        v.cas = cas;
+        table.insert(key, v)?;
+        Ok(new)
+    }
+
+    /// Restarts the countdown of a live entry's ttl, as if it was just written. Returns whether the
+    /// key existed.
+    pub async fn refresh(&self, key: &str) -> bool {
+        let mut table = self.shard(key).write().await;
+        let Some(v) = table.get_mut(key).filter(|v| v.has_value()) else {
+            return false;
+        };
+        v.time_added = Instant::now();
+        table.schedule(key);
+        true
+    }
+
+    /// Replaces the ttl of a live entry, counting from now. Returns whether the key existed.
+    pub async fn touch(&self, key: &str, ttl: Option<Duration>) -> bool {
+        let mut table = self.shard(key).write().await;
+        let Some(v) = table.get_mut(key).filter(|v| v.has_value()) else {
+            return false;
+        };
+        v.ttl = ttl;
+        v.time_added = Instant::now();
+        table.schedule(key);
+        true
+    }
+
+    /// Drops every entry.
+    pub async fn flush(&self) {
+        for shard in self.shards.iter() {
+            shard.write().await.clear();
+        }
+    }
+
+    /// Where the entries stand against `max_memory`, and how many were evicted to stay under it.
+    pub async fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            keys: 0,
+            used_memory: 0,
+            max_memory: self.config().max_memory(),
+            evictions: 0,
+        };
+        for shard in self.shards.iter() {
+            let table = shard.read().await;
+            stats.keys += table.entries.len();
+            stats.used_memory += table.used;
+            stats.evictions += table.evictions;
+        }
+        stats
+    }
+
+    /// Every live entry that has a value, used to bring a newly joined node up to date.
+    pub async fn entries(&self) -> Vec<(String, Data)> {
+        let mut entries = Vec::new();
+        for shard in self.shards.iter() {
+            let table = shard.read().await;
+            entries.extend(
+                table
+                    .entries
+                    .iter()
+                    .filter(|(_, v)| v.has_value())
+                    .map(|(k, v)| (k.clone(), v.clone())),
+            );
+        }
+        entries
+    }
+
+    /// Looks up every key, holding the read locks of all the shards involved at once so the
+    /// values are from the same moment. Expired entries are left for the sweeper.
+    pub async fn get_many(&self, keys: &[String]) -> Vec<Option<Data>> {
+        let mut tables = HashMap::new();
+        for idx in self.shards_of(keys.iter().map(String::as_str)) {
+            tables.insert(idx, self.shards[idx].read().await);
+        }
+        keys.iter()
+            .map(|k| tables[&self.shard_index(k)].get(k).cloned())
+            .collect()
+    }
+
+    /// Looks the key up under a read lock, only an expired entry takes the write lock to be
+    /// removed.
+    pub async fn get_or_remove(&self, k: String) -> Option<Data> {
+        let shard = self.shard(&k);
+        let table = shard.read().await;
+        match table.get(&k) {
+            Some(data) => return Some(data.clone()),
+            None if !table.entries.contains_key(&k) => return None,
+            None => drop(table),
+        }
+
+        let mut table = shard.write().await;
+        // someone may have written it in between
+        if table.entries.get(&k).is_some_and(|v| !v.validate_cache()) {
+            table.remove(&k);
         }
-        table.write().await.remove(&k);
-        // remove
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db(config: &str) -> Database {
+        Database::new(Arc::new(
+            serde_json::from_str(config).expect("valid config"),
+        ))
+    }
+
+    fn db_with_policy(policy: &str) -> Database {
+        db(&format!(
+            r#"{{ "max_memory": 1000, "shards": 1, "eviction_policy": "{}" }}"#,
+            policy
+        ))
+    }
+
+    async fn value(db: &Database, key: &str) -> Option<Vec<u8>> {
+        db.get_or_remove(key.to_string()).await?.inner()
+    }
+
+    async fn store(db: &Database, key: &str, value: &str, mode: StoreMode) -> StoreResult {
+        db.store(key.to_string(), value.into(), 0, None, mode).await
+    }
+
+    #[tokio::test]
+    async fn store_modes() {
+        let db = db("{}");
+        assert_eq!(
+            store(&db, "k", "a", StoreMode::Replace).await,
+            StoreResult::NotStored
+        );
+        assert_eq!(
+            store(&db, "k", "a", StoreMode::Append).await,
+            StoreResult::NotStored
+        );
+        assert_eq!(value(&db, "k").await, None);
+
+        assert_eq!(
+            store(&db, "k", "b", StoreMode::Add).await,
+            StoreResult::Stored
+        );
+        assert_eq!(
+            store(&db, "k", "x", StoreMode::Add).await,
+            StoreResult::NotStored
+        );
+        assert_eq!(
+            store(&db, "k", "c", StoreMode::Append).await,
+            StoreResult::Stored
+        );
+        assert_eq!(
+            store(&db, "k", "a", StoreMode::Prepend).await,
+            StoreResult::Stored
+        );
+        assert_eq!(value(&db, "k").await.as_deref(), Some(&b"abc"[..]));
+
+        assert_eq!(
+            store(&db, "k", "d", StoreMode::Replace).await,
+            StoreResult::Stored
+        );
+        assert_eq!(
+            store(&db, "k", "e", StoreMode::Set).await,
+            StoreResult::Stored
+        );
+        assert_eq!(value(&db, "k").await.as_deref(), Some(&b"e"[..]));
+    }
+
+    #[tokio::test]
+    async fn expired_values_count_as_absent() {
+        let db = db("{}");
+        let ttl = Some(Duration::from_millis(20));
+        db.store("k".into(), b"old".to_vec(), 0, ttl, StoreMode::Set)
+            .await;
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert_eq!(
+            store(&db, "k", "new", StoreMode::Replace).await,
+            StoreResult::NotStored
+        );
+        assert_eq!(
+            store(&db, "k", "new", StoreMode::Add).await,
+            StoreResult::Stored
+        );
+        assert_eq!(value(&db, "k").await.as_deref(), Some(&b"new"[..]));
+    }
+
+    #[tokio::test]
+    async fn cas_tokens() {
+        let db = db("{}");
+        assert_eq!(
+            store(&db, "k", "a", StoreMode::Cas(1)).await,
+            StoreResult::NotFound
+        );
+        store(&db, "k", "a", StoreMode::Set).await;
+        let token = db.get_or_remove("k".into()).await.unwrap().cas();
+
+        // every write hands out a new token, even one leaving the value as it was
+        assert_eq!(
+            store(&db, "k", "a", StoreMode::Set).await,
+            StoreResult::Stored
+        );
+        assert_eq!(
+            store(&db, "k", "b", StoreMode::Cas(token)).await,
+            StoreResult::Exists
+        );
+        let token = db.get_or_remove("k".into()).await.unwrap().cas();
+        assert_eq!(
+            store(&db, "k", "b", StoreMode::Cas(token)).await,
+            StoreResult::Stored
+        );
+        assert_eq!(
+            store(&db, "k", "c", StoreMode::Cas(token)).await,
+            StoreResult::Exists
+        );
+        assert_eq!(value(&db, "k").await.as_deref(), Some(&b"b"[..]));
+
+        assert!(db.get_or_remove("k".into()).await.unwrap().cas() > token);
+    }
+
+    #[tokio::test]
+    async fn counters() {
+        let db = db("{}");
+        let ttl = Some(Duration::from_secs(60));
+        assert_eq!(db.counter("n".into(), 5, 10, ttl).await, Ok(15));
+        assert_eq!(db.counter("n".into(), -20, 0, None).await, Ok(-5));
+        // an existing counter keeps its ttl
+        let left = db.get_or_remove("n".into()).await.unwrap().remaining_ttl();
+        assert!(left.is_some_and(|left| left > Duration::from_secs(59)));
+
+        db.counter("max".into(), i64::MAX, 0, None).await.unwrap();
+        assert_eq!(
+            db.counter("max".into(), 1, 0, None).await,
+            Err(CounterError::Overflow)
+        );
+        store(&db, "s", "text", StoreMode::Set).await;
+        assert_eq!(
+            db.counter("s".into(), 1, 0, None).await,
+            Err(CounterError::NotANumber)
+        );
+    }
+
+    #[tokio::test]
+    async fn memcached_counters() {
+        let db = db("{}");
+        assert_eq!(db.incr("n", 1, false).await, Err(CounterError::NotFound));
+        store(&db, "n", "10", StoreMode::Set).await;
+        assert_eq!(db.incr("n", 20, true).await, Ok(0));
+        assert_eq!(db.incr("n", u64::MAX, false).await, Ok(u64::MAX));
+        assert_eq!(db.incr("n", 2, false).await, Ok(1));
+        store(&db, "neg", "-1", StoreMode::Set).await;
+        assert_eq!(
+            db.incr("neg", 1, false).await,
+            Err(CounterError::NotANumber)
+        );
+    }
+
+    #[tokio::test]
+    async fn batches_are_written_whole_or_not_at_all() {
+        let db = db(r#"{ "max_memory": 1000, "shards": 1 }"#);
+        let pairs = |pairs: &[(&str, usize)]| {
+            pairs
+                .iter()
+                .map(|(k, len)| (k.to_string(), vec![b'v'; *len]))
+                .collect::<Vec<_>>()
+        };
+        db.store_many(pairs(&[("a", 1), ("b", 2)]), None)
+            .await
+            .unwrap();
+        assert_eq!(value(&db, "b").await, Some(b"vv".to_vec()));
+
+        // the last key can never fit
+        let res = db
+            .store_many(pairs(&[("a", 3), ("c", 1), ("d", 1000)]), None)
+            .await;
+        assert_eq!(res, Err(OutOfMemory));
+        assert_eq!(value(&db, "a").await, Some(b"v".to_vec()));
+        assert_eq!(value(&db, "c").await, None);
+
+        // each fits, but the second evicts the first
+        let res = db.store_many(pairs(&[("c", 600), ("d", 600)]), None).await;
+        assert_eq!(res, Err(OutOfMemory));
+        assert_eq!(value(&db, "c").await, None);
+        assert_eq!(value(&db, "d").await, None);
+        assert!(db.stats().await.used_memory <= 1000);
+    }
+
+    #[tokio::test]
+    async fn ttls_can_be_changed() {
+        let db = db("{}");
+        assert!(!db.touch("k", None).await);
+        assert!(!db.refresh("k").await);
+
+        let short = Some(Duration::from_secs(10));
+        db.store("k".into(), b"v".to_vec(), 0, short, StoreMode::Set)
+            .await;
+        assert!(db.touch("k", None).await);
+        assert_eq!(
+            db.get_or_remove("k".into()).await.unwrap().remaining_ttl(),
+            None
+        );
+        assert!(db.touch("k", Some(Duration::from_secs(60))).await);
+        let left = db.get_or_remove("k".into()).await.unwrap().remaining_ttl();
+        assert!(left.is_some_and(|left| left > Duration::from_secs(59)));
+        assert!(db.refresh("k").await);
+
+        assert!(db.touch("k", Some(Duration::ZERO)).await);
+        assert!(db.get_or_remove("k".into()).await.is_none());
+        assert!(!db.touch("k", None).await);
+    }
+
+    #[tokio::test]
+    async fn lru_eviction_stays_under_max_memory() {
+        let db = db(r#"{ "max_memory": 1000, "shards": 1 }"#);
+        let entry = Data::with_value(vec![0; 100], 0, None, 0).size("k0");
+        let fit = 1000 / entry;
+        for i in 0..fit {
+            db.store(format!("k{}", i), vec![0; 100], 0, None, StoreMode::Set)
+                .await;
+        }
+        assert_eq!(db.stats().await.evictions, 0);
+
+        // reading k0 makes k1 the least recently used
+        assert!(value(&db, "k0").await.is_some());
+        db.store("new".into(), vec![0; 100], 0, None, StoreMode::Set)
+            .await;
+        let stats = db.stats().await;
+        assert_eq!((stats.keys, stats.evictions), (fit, 1));
+        assert!(stats.used_memory <= 1000);
+        assert!(value(&db, "k0").await.is_some());
+        assert!(value(&db, "k1").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn policies_that_cannot_evict_fail_the_write() {
+        let db = db_with_policy("volatile-ttl");
+        let ttl = |secs| Some(Duration::from_secs(secs));
+        db.store("soon".into(), vec![0; 200], 0, ttl(10), StoreMode::Set)
+            .await;
+        db.store("later".into(), vec![0; 200], 0, ttl(100), StoreMode::Set)
+            .await;
+        db.store("forever".into(), vec![0; 200], 0, None, StoreMode::Set)
+            .await;
+        // makes room by dropping a key with a ttl, sampling makes it likely but not certain to be
+        // the one closest to expiring
+        let res = db
+            .store("new".into(), vec![0; 200], 0, None, StoreMode::Set)
+            .await;
+        assert_eq!(res, StoreResult::Stored);
+        assert!(value(&db, "forever").await.is_some());
+        assert_eq!(db.stats().await.evictions, 1);
+        // evicting every key with a ttl isn't enough
+        let res = db
+            .store("big".into(), vec![0; 500], 0, None, StoreMode::Set)
+            .await;
+        assert_eq!(res, StoreResult::OutOfMemory);
+
+        let db = db_with_policy("no-eviction");
+        assert_eq!(
+            store(&db, "a", &"v".repeat(800), StoreMode::Set).await,
+            StoreResult::Stored
+        );
+        assert_eq!(
+            store(&db, "b", &"v".repeat(800), StoreMode::Set).await,
+            StoreResult::OutOfMemory
+        );
+        assert!(value(&db, "a").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn entries_bigger_than_the_limit_evict_nothing() {
+        let db = db_with_policy("lru");
+        store(&db, "a", "v", StoreMode::Set).await;
+        let res = store(&db, "big", &"v".repeat(1000), StoreMode::Set).await;
+        assert_eq!(res, StoreResult::OutOfMemory);
+        assert!(value(&db, "a").await.is_some());
+        assert_eq!(db.stats().await.evictions, 0);
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_swept() {
+        let db = db("{}");
+        let ttl = |ms| Some(Duration::from_millis(ms));
+        db.store("gone".into(), b"v".to_vec(), 0, ttl(10), StoreMode::Set)
+            .await;
+        db.store("kept".into(), b"v".to_vec(), 0, ttl(10), StoreMode::Set)
+            .await;
+        // the old deadline stays queued, but no longer applies
+        assert!(db.touch("kept", ttl(60_000)).await);
+        db.store("forever".into(), b"v".to_vec(), 0, None, StoreMode::Set)
+            .await;
+
+        db.keep_valid().await;
+        tokio::time::sleep(EXPIRE_TICK * 3).await;
+        let stats = db.stats().await;
+        assert_eq!(stats.keys, 2);
+        assert!(value(&db, "kept").await.is_some());
+    }
+
+    #[test]
+    fn sweeps_are_bounded() {
+        let mut table = Table::new(None, EvictionPolicy::Lru);
+        for i in 0..MAX_EXPIRED_PER_TICK + 10 {
+            let data = Data::with_value(vec![], 0, Some(Duration::ZERO), 0);
+            table.insert(i.to_string(), data).unwrap();
+        }
+        table.expire(MAX_EXPIRED_PER_TICK);
+        assert_eq!(table.entries.len(), 10);
+        table.expire(MAX_EXPIRED_PER_TICK);
+        assert!(table.entries.is_empty());
+        assert!(table.expiry.is_empty());
+    }
+
+    #[test]
+    fn ttls_past_the_clock_never_expire() {
+        let data = Data::with_value(vec![], 0, Some(Duration::MAX), 0);
+        assert_eq!(data.deadline(), None);
+        assert!(data.validate_cache());
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+use rand::Rng;
+use tokio::time::{Duration, Instant};
+
+use crate::{config::EvictionPolicy, database::Data};
+
+/// How many random keys the sampling policies compare before picking a victim.
+const SAMPLES: usize = 5;
+/// What a new key's LFU counter starts at, so it isn't the first to go before it had a chance to
+/// be read.
+const LFU_INIT: u8 = 5;
+/// The higher, the more hits it takes to grow an LFU counter that is already high.
+const LFU_LOG_FACTOR: f64 = 10.0;
+/// LFU counters lose a point for every period the key went unused.
+const LFU_DECAY: Duration = Duration::from_secs(60);
+
+/// Decides which entry goes when the database is over `max_memory`. The table tells the policy
+/// about every key it writes, reads and drops, and asks it for victims until the new entry fits.
+pub trait Eviction: fmt::Debug + Send + Sync {
+    /// The key was written, new or replaced.
+    fn insert(&mut self, key: &str);
+    /// The key was read or changed in place.
+    fn touch(&mut self, key: &str);
+    fn remove(&mut self, key: &str);
+    fn clear(&mut self);
+    /// The key to evict next, never `keep` - the key being written. `None` when nothing may be
+    /// evicted, which fails the write.
+    fn victim(&mut self, entries: &HashMap<String, Data>, keep: &str) -> Option<String>;
+}
+
+pub fn policy(policy: EvictionPolicy) -> Box<dyn Eviction> {
+    match policy {
+        EvictionPolicy::Lru => Box::<Lru>::default(),
+        EvictionPolicy::Lfu => Box::<Lfu>::default(),
+        EvictionPolicy::Random => Box::<Random>::default(),
+        EvictionPolicy::VolatileTtl => Box::<VolatileTtl>::default(),
+        EvictionPolicy::NoEviction => Box::new(NoEviction),
+    }
+}
+
+/// Every key in a vec, so random ones can be sampled without walking the map.
+#[derive(Debug, Default)]
+struct Keys {
+    keys: Vec<String>,
+    index: HashMap<String, usize>,
+}
+
+impl Keys {
+    fn insert(&mut self, key: &str) {
+        if !self.index.contains_key(key) {
+            self.index.insert(key.to_string(), self.keys.len());
+            self.keys.push(key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        let Some(idx) = self.index.remove(key) else {
+            return;
+        };
+        self.keys.swap_remove(idx);
+        if let Some(moved) = self.keys.get(idx) {
+            self.index.insert(moved.clone(), idx);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear();
+        self.index.clear();
+    }
+
+    /// Up to `SAMPLES` random keys other than `keep`, possibly repeating. Only empty if there is
+    /// no other key.
+    fn sample(&self, keep: &str) -> Vec<&String> {
+        let mut rng = rand::thread_rng();
+        let n = self.keys.len();
+        let mut picks = (0..SAMPLES.min(n))
+            .map(|_| &self.keys[rng.gen_range(0..n)])
+            .filter(|k| *k != keep)
+            .collect::<Vec<_>>();
+        if picks.is_empty() {
+            picks.extend(self.keys.iter().find(|k| *k != keep));
+        }
+        picks
+    }
+}
+
+/// Least recently used goes first.
+#[derive(Debug, Default)]
+struct Lru {
+    // last tick handed out
+    clock: u64,
+    // tick of the last use -> key, oldest first
+    order: BTreeMap<u64, String>,
+    ticks: HashMap<String, u64>,
+}
+
+impl Eviction for Lru {
+    fn insert(&mut self, key: &str) {
+        self.touch(key)
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.remove(key);
+        self.clock += 1;
+        self.order.insert(self.clock, key.to_string());
+        self.ticks.insert(key.to_string(), self.clock);
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.order.clear();
+        self.ticks.clear();
+    }
+
+    fn victim(&mut self, _: &HashMap<String, Data>, keep: &str) -> Option<String> {
+        self.order.values().find(|k| *k != keep).cloned()
+    }
+}
+
+/// Least frequently used goes first. Like redis, the frequency is a small logarithmic counter
+/// that grows less likely to increase the higher it is and decays while the key is idle, and the
+/// victim is the least used of a few sampled keys.
+#[derive(Debug, Default)]
+struct Lfu {
+    keys: Keys,
+    counters: HashMap<String, (u8, Instant)>,
+}
+
+impl Lfu {
+    /// The counter with the idle periods since its last use taken off.
+    fn decayed((counter, last_used): (u8, Instant)) -> u8 {
+        let periods = last_used.elapsed().as_secs() / LFU_DECAY.as_secs();
+        counter.saturating_sub(periods.min(u8::MAX.into()) as u8)
+    }
+}
+
+impl Eviction for Lfu {
+    fn insert(&mut self, key: &str) {
+        if self.counters.contains_key(key) {
+            return self.touch(key);
+        }
+        self.keys.insert(key);
+        self.counters
+            .insert(key.to_string(), (LFU_INIT, Instant::now()));
+    }
+
+    fn touch(&mut self, key: &str) {
+        let Some(entry) = self.counters.get_mut(key) else {
+            return;
+        };
+        let mut counter = Self::decayed(*entry);
+        let base = counter.saturating_sub(LFU_INIT) as f64;
+        if counter < u8::MAX
+            && rand::thread_rng().gen::<f64>() < 1.0 / (base * LFU_LOG_FACTOR + 1.0)
+        {
+            counter += 1;
+        }
+        *entry = (counter, Instant::now());
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.keys.remove(key);
+        self.counters.remove(key);
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear();
+        self.counters.clear();
+    }
+
+    fn victim(&mut self, _: &HashMap<String, Data>, keep: &str) -> Option<String> {
+        self.keys
+            .sample(keep)
+            .into_iter()
+            .min_by_key(|k| self.counters.get(*k).copied().map_or(0, Self::decayed))
+            .cloned()
+    }
+}
+
+/// Any key may go.
+#[derive(Debug, Default)]
+struct Random {
+    keys: Keys,
+}
+
+impl Eviction for Random {
+    fn insert(&mut self, key: &str) {
+        self.keys.insert(key)
+    }
+
+    fn touch(&mut self, _: &str) {}
+
+    fn remove(&mut self, key: &str) {
+        self.keys.remove(key)
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear()
+    }
+
+    fn victim(&mut self, _: &HashMap<String, Data>, keep: &str) -> Option<String> {
+        self.keys.sample(keep).first().map(|k| k.to_string())
+    }
+}
+
+/// Only keys with a ttl may go, the one closest to expiring first (of a few sampled). Keys that
+/// never expire are never evicted, once only those are left writes fail.
+#[derive(Debug, Default)]
+struct VolatileTtl {
+    keys: Keys,
+}
+
+impl Eviction for VolatileTtl {
+    fn insert(&mut self, key: &str) {
+        self.keys.insert(key)
+    }
+
+    fn touch(&mut self, _: &str) {}
+
+    fn remove(&mut self, key: &str) {
+        self.keys.remove(key)
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear()
+    }
+
+    fn victim(&mut self, entries: &HashMap<String, Data>, keep: &str) -> Option<String> {
+        let sampled = self
+            .keys
+            .sample(keep)
+            .into_iter()
+            .filter_map(|k| Some((entries.get(k)?.remaining_ttl()?, k)))
+            .min();
+        if let Some((_, key)) = sampled {
+            return Some(key.clone());
+        }
+        // the sample can miss the few keys that do expire, look at all of them before giving up
+        entries
+            .iter()
+            .filter(|(k, _)| *k != keep)
+            .filter_map(|(k, v)| Some((v.remaining_ttl()?, k)))
+            .min()
+            .map(|(_, k)| k.clone())
+    }
+}
+
+/// Nothing goes, writes fail once the database is full.
+#[derive(Debug)]
+struct NoEviction;
+
+impl Eviction for NoEviction {
+    fn insert(&mut self, _: &str) {}
+    fn touch(&mut self, _: &str) {}
+    fn remove(&mut self, _: &str) {}
+    fn clear(&mut self) {}
+
+    fn victim(&mut self, _: &HashMap<String, Data>, _: &str) -> Option<String> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn victims(policy: &mut dyn Eviction, keep: &str) -> Vec<String> {
+        let entries = HashMap::new();
+        let mut victims = Vec::new();
+        while let Some(victim) = policy.victim(&entries, keep) {
+            policy.remove(&victim);
+            victims.push(victim);
+        }
+        victims
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used() {
+        let mut lru = Lru::default();
+        for key in ["a", "b", "c", "d"] {
+            lru.insert(key);
+        }
+        lru.touch("a");
+        lru.insert("b");
+        lru.remove("c");
+        assert_eq!(victims(&mut lru, "a"), ["d", "b"]);
+
+        lru.insert("e");
+        lru.clear();
+        assert_eq!(victims(&mut lru, "a"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn sampling_policies_never_pick_the_kept_key() {
+        for mut policy in [policy(EvictionPolicy::Lfu), policy(EvictionPolicy::Random)] {
+            for key in ["a", "b", "c"] {
+                policy.insert(key);
+            }
+            let mut left = victims(policy.as_mut(), "b");
+            left.sort();
+            assert_eq!(left, ["a", "c"], "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn lfu_keeps_what_is_used() {
+        let mut lfu = Lfu::default();
+        for i in 0..100 {
+            lfu.insert(&i.to_string());
+        }
+        for _ in 0..1000 {
+            lfu.touch("0");
+        }
+        for _ in 0..50 {
+            let victim = lfu.victim(&HashMap::new(), "new").unwrap();
+            assert_ne!(victim, "0");
+            lfu.remove(&victim);
+        }
+    }
+
+    #[test]
+    fn no_eviction_has_no_victims() {
+        let mut policy = policy(EvictionPolicy::NoEviction);
+        policy.insert("a");
+        assert_eq!(policy.victim(&HashMap::new(), "b"), None);
+    }
+}
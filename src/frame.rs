@@ -0,0 +1,378 @@
+use core::fmt;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+
+use crate::{config::Protocol, memcache::MemcacheCommand, message::ClientMessage};
+
+/// Longest command line we accept, including the line ending.
+pub const MAX_LINE_LEN: usize = 8 * 1024;
+/// Largest value a command can announce with a byte count, and the largest the database holds -
+/// appending to a value can't grow it past this either.
+pub const MAX_VALUE_LEN: usize = 1024 * 1024;
+/// Most arguments a single RESP command may have.
+pub const MAX_RESP_ARGS: usize = 1024;
+/// Most bytes all the arguments of a RESP command may add up to, enough for a key and a value.
+pub const MAX_RESP_LEN: usize = MAX_VALUE_LEN + MAX_LINE_LEN;
+
+/// A single request read off a connection: the command line and, for commands that announce a
+/// byte count (`SET key ttl bytes`), the value that followed it. Both are raw bytes, the line can
+/// carry a value too (`key:value`) and values are never required to be UTF-8.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub line: Vec<u8>,
+    pub payload: Option<Vec<u8>>,
+}
+
+impl Frame {
+    /// The line as text, `None` if it isn't UTF-8 and so can't be a command.
+    pub fn text(&self) -> Option<&str> {
+        std::str::from_utf8(&self.line).ok()
+    }
+}
+
+/// What a connection sent, in whichever protocol the connection turned out to speak.
+#[derive(Debug, Clone)]
+pub enum Request {
+    Text(Frame),
+    /// A RESP array of bulk strings, the way redis clients send commands
+    Resp(Vec<Vec<u8>>),
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(std::io::Error),
+    LineTooLong,
+    ValueTooLarge(usize),
+    /// The announced value was not followed by a line ending.
+    BadTerminator,
+    /// A RESP connection sent something other than an array of bulk strings.
+    BadResp,
+    /// The arguments of a RESP command add up to more than [`MAX_RESP_LEN`].
+    RequestTooLarge,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Io(err) => write!(f, "{}", err),
+            FrameError::LineTooLong => write!(f, "line longer than {} bytes", MAX_LINE_LEN),
+            FrameError::ValueTooLarge(n) => {
+                write!(
+                    f,
+                    "value of {} bytes is over the {} limit",
+                    n, MAX_VALUE_LEN
+                )
+            }
+            FrameError::BadTerminator => write!(f, "value was not terminated by a newline"),
+            FrameError::BadResp => write!(f, "expected an array of bulk strings"),
+            FrameError::RequestTooLarge => {
+                write!(f, "arguments add up to more than {} bytes", MAX_RESP_LEN)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for FrameError {
+    fn from(err: std::io::Error) -> Self {
+        FrameError::Io(err)
+    }
+}
+
+/// Splits a byte stream into [`Frame`]s. Commands are read a line at a time (`\n` or `\r\n`
+/// terminated); when the line announces a byte count the reader switches to reading exactly that
+/// many bytes, so values may contain anything - newlines included.
+///
+/// A connection whose first byte is `*` is a redis client and gets read as RESP instead, no
+/// text command starts with it.
+pub struct FrameReader<R> {
+    inner: BufReader<R>,
+    protocol: Protocol,
+    // `None` until the first byte of the connection was seen
+    resp: Option<bool>,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(read: R, protocol: Protocol) -> Self {
+        Self {
+            inner: BufReader::new(read),
+            protocol,
+            resp: None,
+        }
+    }
+
+    pub fn is_resp(&self) -> bool {
+        self.resp == Some(true)
+    }
+
+    /// Returns `Ok(None)` once the other side closed the connection.
+    pub async fn next_frame(&mut self) -> Result<Option<Request>, FrameError> {
+        let resp = match self.resp {
+            Some(resp) => resp,
+            None => {
+                let buf = self.inner.fill_buf().await?;
+                let Some(first) = buf.first() else {
+                    return Ok(None);
+                };
+                *self.resp.insert(*first == b'*')
+            }
+        };
+
+        if resp {
+            Ok(self.read_resp().await?.map(Request::Resp))
+        } else {
+            Ok(self.read_text().await?.map(Request::Text))
+        }
+    }
+
+    async fn read_text(&mut self) -> Result<Option<Frame>, FrameError> {
+        let line = loop {
+            match self.read_line().await? {
+                Some(line) if line.trim_ascii().is_empty() => continue,
+                Some(line) => break line,
+                None => return Ok(None),
+            }
+        };
+
+        // A node joining through a memcached port still replicates with `SYNC` afterwards
+        let text = std::str::from_utf8(&line).ok();
+        let payload_len = text.and_then(|line| match self.protocol {
+            Protocol::Native => ClientMessage::payload_len(line),
+            Protocol::Memcached => {
+                MemcacheCommand::payload_len(line).or_else(|| ClientMessage::payload_len(line))
+            }
+        });
+        // the payload of a `SYNC` carries the key as well as the value
+        let max_len = match line.starts_with(b"SYNC ") {
+            true => MAX_VALUE_LEN + MAX_LINE_LEN,
+            false => MAX_VALUE_LEN,
+        };
+        let payload = match payload_len {
+            Some(len) if len > max_len => return Err(FrameError::ValueTooLarge(len)),
+            Some(len) => Some(self.read_value(len).await?),
+            None => None,
+        };
+
+        Ok(Some(Frame { line, payload }))
+    }
+
+    async fn read_resp(&mut self) -> Result<Option<Vec<Vec<u8>>>, FrameError> {
+        let Some(header) = self.read_line().await? else {
+            return Ok(None);
+        };
+        let count = resp_len(&header, b'*')
+            .filter(|n| *n <= MAX_RESP_ARGS)
+            .ok_or(FrameError::BadResp)?;
+
+        let mut args = Vec::with_capacity(count);
+        let mut total = 0;
+        for _ in 0..count {
+            let line = self.read_line().await?.ok_or(FrameError::BadResp)?;
+            let len = resp_len(&line, b'$').ok_or(FrameError::BadResp)?;
+            if len > MAX_VALUE_LEN {
+                return Err(FrameError::ValueTooLarge(len));
+            }
+            total += len;
+            if total > MAX_RESP_LEN {
+                return Err(FrameError::RequestTooLarge);
+            }
+            args.push(self.read_value(len).await?);
+        }
+        Ok(Some(args))
+    }
+
+    async fn read_line(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        let mut buf = Vec::new();
+        let n = (&mut self.inner)
+            .take(MAX_LINE_LEN as u64)
+            .read_until(b'\n', &mut buf)
+            .await?;
+
+        if n == 0 {
+            return Ok(None);
+        }
+        if buf.last() != Some(&b'\n') && n == MAX_LINE_LEN {
+            return Err(FrameError::LineTooLong);
+        }
+
+        strip_line_ending(&mut buf);
+        Ok(Some(buf))
+    }
+
+    async fn read_value(&mut self, len: usize) -> Result<Vec<u8>, FrameError> {
+        let mut buf = vec![0; len];
+        self.inner.read_exact(&mut buf).await?;
+
+        match self.inner.read_u8().await? {
+            b'\n' => {}
+            b'\r' if self.inner.read_u8().await? == b'\n' => {}
+            _ => return Err(FrameError::BadTerminator),
+        }
+
+        Ok(buf)
+    }
+}
+
+/// Parses a RESP length header like `*3` or `$5`.
+fn resp_len(line: &[u8], prefix: u8) -> Option<usize> {
+    let (first, n) = line.split_first()?;
+    if *first != prefix {
+        return None;
+    }
+    std::str::from_utf8(n).ok()?.parse().ok()
+}
+
+fn strip_line_ending(buf: &mut Vec<u8>) {
+    if buf.last() == Some(&b'\n') {
+        buf.pop();
+        if buf.last() == Some(&b'\r') {
+            buf.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    fn reader(input: &[u8]) -> FrameReader<&[u8]> {
+        FrameReader::new(input, Protocol::Native)
+    }
+
+    async fn text(reader: &mut FrameReader<impl AsyncRead + Unpin>) -> Frame {
+        match reader.next_frame().await {
+            Ok(Some(Request::Text(frame))) => frame,
+            other => panic!("expected a text frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn lines_end_with_either_line_ending() {
+        let mut r = reader(b"GET a\r\nGET b\n\r\n\nGET c");
+        assert_eq!(text(&mut r).await.line, b"GET a");
+        assert_eq!(text(&mut r).await.line, b"GET b");
+        // blank lines are skipped, the last line doesn't need an ending
+        assert_eq!(text(&mut r).await.line, b"GET c");
+        assert!(r.next_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn announced_values_are_read_verbatim() {
+        let mut r = reader(b"SET k 10 5\r\na\nb\r\n\r\nSYNC 1 -1 0 2\nkhi\nGET k\n");
+        let frame = text(&mut r).await;
+        assert_eq!(frame.line, b"SET k 10 5");
+        assert_eq!(frame.payload.as_deref(), Some(&b"a\nb\r\n"[..]));
+        assert_eq!(text(&mut r).await.payload.as_deref(), Some(&b"khi"[..]));
+        assert_eq!(text(&mut r).await.payload, None);
+    }
+
+    #[tokio::test]
+    async fn frames_split_across_reads() {
+        let (mut tx, rx) = tokio::io::duplex(64);
+        let mut r = FrameReader::new(rx, Protocol::Native);
+        let writer = tokio::spawn(async move {
+            for part in [
+                &b"SE"[..],
+                b"T k 10 ",
+                b"3\r",
+                b"\nab",
+                b"c\r",
+                b"\nGET k\n",
+            ] {
+                tx.write_all(part).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        let frame = text(&mut r).await;
+        assert_eq!(frame.line, b"SET k 10 3");
+        assert_eq!(frame.payload.as_deref(), Some(&b"abc"[..]));
+        assert_eq!(text(&mut r).await.line, b"GET k");
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        let long = vec![b'a'; MAX_LINE_LEN + 1];
+        assert!(matches!(
+            reader(&long).next_frame().await,
+            Err(FrameError::LineTooLong)
+        ));
+
+        let mut line = vec![b'a'; MAX_LINE_LEN - 1];
+        line.push(b'\n');
+        assert_eq!(text(&mut reader(&line)).await.line.len(), MAX_LINE_LEN - 1);
+
+        let too_large = format!("SET k 10 {}\n", MAX_VALUE_LEN + 1);
+        assert!(matches!(
+            reader(too_large.as_bytes()).next_frame().await,
+            Err(FrameError::ValueTooLarge(n)) if n == MAX_VALUE_LEN + 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn value_must_be_followed_by_a_line_ending() {
+        assert!(matches!(
+            reader(b"SET k 10 3\nabcd\n").next_frame().await,
+            Err(FrameError::BadTerminator)
+        ));
+        assert!(matches!(
+            reader(b"SET k 10 3\nabc\rx").next_frame().await,
+            Err(FrameError::BadTerminator)
+        ));
+    }
+
+    #[tokio::test]
+    async fn memcached_storage_commands_carry_a_value() {
+        let mut r = FrameReader::new(
+            &b"set k 0 0 3 noreply\r\nabc\r\nget k\r\n"[..],
+            Protocol::Memcached,
+        );
+        assert_eq!(text(&mut r).await.payload.as_deref(), Some(&b"abc"[..]));
+        assert_eq!(text(&mut r).await.line, b"get k");
+    }
+
+    #[tokio::test]
+    async fn resp_is_detected_from_the_first_byte() {
+        let mut r = reader(b"*2\r\n$3\r\nGET\r\n$4\r\na\r\nb\r\n*1\r\n$4\r\nPING\r\n");
+        match r.next_frame().await {
+            Ok(Some(Request::Resp(args))) => assert_eq!(args, [&b"GET"[..], b"a\r\nb"]),
+            other => panic!("expected a RESP request, got {:?}", other),
+        }
+        assert!(r.is_resp());
+        assert!(matches!(r.next_frame().await, Ok(Some(Request::Resp(_)))));
+        assert!(r.next_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn resp_must_be_an_array_of_bulk_strings() {
+        for input in [&b"*1\r\n:1\r\n"[..], b"*x\r\n", b"*2\r\n$1\r\na\r\n"] {
+            assert!(
+                matches!(reader(input).next_frame().await, Err(FrameError::BadResp)),
+                "{:?}",
+                String::from_utf8_lossy(input)
+            );
+        }
+        let too_many = format!("*{}\r\n", MAX_RESP_ARGS + 1);
+        assert!(matches!(
+            reader(too_many.as_bytes()).next_frame().await,
+            Err(FrameError::BadResp)
+        ));
+    }
+
+    #[tokio::test]
+    async fn resp_requests_are_capped_in_total() {
+        let arg = format!("${}\r\n{}\r\n", MAX_VALUE_LEN, "a".repeat(MAX_VALUE_LEN));
+        let input = format!("*3\r\n$3\r\nSET\r\n{}{}", arg, arg);
+        assert!(matches!(
+            reader(input.as_bytes()).next_frame().await,
+            Err(FrameError::RequestTooLarge)
+        ));
+
+        let input = format!("*3\r\n$3\r\nSET\r\n$1\r\nk\r\n{}", arg);
+        match reader(input.as_bytes()).next_frame().await {
+            Ok(Some(Request::Resp(args))) => assert_eq!(args[2].len(), MAX_VALUE_LEN),
+            other => panic!("expected a RESP request, got {:?}", other),
+        }
+    }
+}
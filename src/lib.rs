@@ -0,0 +1,17 @@
+//! The cache itself. `main.rs` wires it up into the server binary, the benchmarks drive it
+//! directly.
+#![deny(unused_must_use)]
+#![allow(clippy::let_underscore_future, clippy::result_unit_err)]
+
+pub mod aof;
+pub mod client;
+pub mod config;
+pub mod database;
+pub mod eviction;
+pub mod frame;
+pub mod memcache;
+pub mod message;
+pub mod resp;
+pub mod server;
+pub mod snapshot;
+pub mod tls;
@@ -1,58 +1,157 @@
 #![deny(unused_must_use)]
-#![allow(clippy::let_underscore_future)]
-use std::{net::SocketAddr, process::exit, str::FromStr, sync::Arc};
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::Path,
+    process::exit,
+    sync::Arc,
+};
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use socket2::{Domain, Socket, Type};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixListener,
+};
 use tracing::{debug, info_span, trace_span, Level};
+use tracing_subscriber::{
+    filter::LevelFilter, layer::SubscriberExt, reload, util::SubscriberInitExt,
+};
 
-use crate::client::Client;
+use rscache::{
+    client::{Client, Connection, PeerAddr},
+    config::{self, Protocol},
+    message::ClientMessage,
+    server,
+    tls::{self, Tls},
+};
+use tokio_rustls::rustls::pki_types::ServerName;
 
-mod client;
-mod config;
-mod database;
-mod message;
-mod server;
+/// How many `DEFERED` redirects we follow before giving up on finding a slot in the tree.
+const MAX_JOIN_HOPS: usize = 16;
 
+/// Joins the network through the configured parent. A full parent answers `DEFERED <addr>` with
+/// one of its children, in which case we repeat the handshake there until some node accepts us.
+/// `listen_addrs` are the addresses we accept connections on, the first is the one we tell the
+/// parent about. Returns the link to the parent and its address.
 pub async fn connect_to_parent(
-    cfg: &crate::config::Config,
-) -> Result<tokio::net::TcpStream, Box<dyn std::error::Error>> {
+    cfg: &config::Config,
+    listen_addrs: &[SocketAddr],
+    tls: Option<&Tls>,
+) -> Result<(Box<dyn Connection>, SocketAddr), Box<dyn std::error::Error>> {
     let parent = cfg.parent().ok_or("No parent set")?;
+    // the parent's name may stand for several addresses, the first one that answers is used
+    let mut targets = parent
+        .resolve()
+        .await?
+        .into_iter()
+        .map(|addr| (addr, tls::server_name(parent.host(), addr)))
+        .collect::<Vec<_>>();
+    targets.reverse();
+    let mut visited = HashSet::new();
 
-    let mut connection =
-        tokio::net::TcpStream::connect::<SocketAddr>(parent.to_socketaddr()).await?;
-
-    // By making the connection, we are currently registered as a normal "client" to the parent and
-    // not as Node. The parent is expecting a "JOIN" message from us to register us as a node.
-    connection.write_all(b"JOIN").await?;
-    // Now we must wait for the parent to respond
-    let mut buf = [0; 1024];
+    let mut hops = 0;
+    while let Some((target, name)) = targets.pop() {
+        if hops == MAX_JOIN_HOPS {
+            break;
+        }
+        if is_own_addr(target, listen_addrs) {
+            return Err("We are the configured parent".into());
+        }
+        if !visited.insert(target) {
+            tracing::error!(message = "JOIN redirect loop", %target);
+            return Err(format!("Deferred back to {} which already refused us", target).into());
+        }
 
-    let timeout = tokio::time::Duration::from_secs(10);
-    if let Ok(n) = tokio::time::timeout(timeout, connection.read(&mut buf)).await? {
-        let response = std::str::from_utf8(&buf[..n])?;
-        match response {
-            "OK" => {
-                tracing::info!(message = "Connected to parent", %parent);
+        match join_node(target, name, listen_addrs[0], tls).await {
+            Ok(JoinResponse::Joined(connection)) => {
+                tracing::info!(message = "Connected to parent", %target);
+                return Ok((connection, target));
             }
-            "DEFERED" => {
-                // TODO:
-                let addr = connection.peer_addr().unwrap();
-                let addr = format!("DEFERED {}", addr);
-                tracing::debug!(message = "Parent deferred", %addr);
-                return Err("Parent deferred".into());
+            Ok(JoinResponse::Defered(addr)) => {
+                tracing::debug!(message = "Parent deferred", from = %target, to = %addr);
+                // children are handed out by the address they listen on, their certificates
+                // have to be issued for it
+                targets = vec![(addr, tls::server_name(&addr.ip().to_string(), addr))];
+                hops += 1;
             }
-            _ => {
-                tracing::error!(message = "Parent did not respond with OK", %response);
-                return Err("Parent did not respond with OK".into());
+            Err(err) if !targets.is_empty() => {
+                tracing::warn!(message = "Could not join, trying the next address", %target, %err);
             }
+            Err(err) => return Err(err),
         }
-    } else {
-        tracing::error!(message = "Timeout while waiting for response from parent");
-        tracing::warn!(message = "Server Starting without parent");
+    }
+
+    tracing::error!(message = "Gave up joining", hops = MAX_JOIN_HOPS);
+    Err(format!("Still deferred after {} hops", MAX_JOIN_HOPS).into())
+}
+
+enum JoinResponse {
+    Joined(Box<dyn Connection>),
+    Defered(SocketAddr),
+}
+
+/// Does a single `JOIN` handshake against `target`, over TLS if it's configured. `name` is what
+/// the target's certificate has to be issued for.
+async fn join_node(
+    target: SocketAddr,
+    name: ServerName<'static>,
+    listen_addr: SocketAddr,
+    tls: Option<&Tls>,
+) -> Result<JoinResponse, Box<dyn std::error::Error>> {
+    let connection = tokio::net::TcpStream::connect(target).await?;
+    // listening on every interface, the address the parent can reach us on is the one this
+    // connection comes from
+    let listen_addr = match listen_addr.ip().is_unspecified() {
+        true => SocketAddr::new(connection.local_addr()?.ip(), listen_addr.port()),
+        false => listen_addr,
+    };
+    let mut connection: Box<dyn Connection> = match tls {
+        Some(tls) => tls.connect(connection, name).await?,
+        None => Box::new(connection),
+    };
+
+    // By making the connection, we are currently registered as a normal "client" to the parent and
+    // not as Node. The parent is expecting a "JOIN" message from us to register us as a node, the
+    // address we send is the one other nodes can reach us on if the parent defers them to us.
+    connection
+        .write_all(format!("JOIN {}\n", listen_addr).as_bytes())
+        .await?;
+    // Now we must wait for the parent to respond
+    let timeout = tokio::time::Duration::from_secs(10);
+    let Ok(response) = tokio::time::timeout(timeout, read_reply_line(&mut connection)).await else {
+        tracing::error!(message = "Timeout while waiting for response from parent", %target);
         return Err("Timeout while waiting for response from parent".into());
+    };
+
+    let response = response?;
+    let response = response.trim();
+    if response == "OK" {
+        return Ok(JoinResponse::Joined(connection));
     }
 
-    Ok(connection)
+    match response.parse::<ClientMessage>() {
+        Ok(ClientMessage::Defered { addr }) => Ok(JoinResponse::Defered(addr)),
+        _ => {
+            tracing::error!(message = "Parent did not respond with OK", %response);
+            Err("Parent did not respond with OK".into())
+        }
+    }
+}
+
+/// Reads the handshake reply one byte at a time, so the replication data the parent sends right
+/// after `OK` is left in the socket for the node link to pick up.
+async fn read_reply_line(
+    connection: &mut Box<dyn Connection>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut line = Vec::new();
+    while line.len() < 1024 {
+        match connection.read_u8().await? {
+            b'\n' => break,
+            b => line.push(b),
+        }
+    }
+    Ok(String::from_utf8(line)?)
 }
 
 #[tokio::main]
@@ -62,75 +161,226 @@ async fn main() -> std::io::Result<()> {
     } else {
         (Level::TRACE, trace_span!("Main"))
     };
-    tracing_subscriber::fmt().with_max_level(level).init();
+    // behind a reload layer so the config can change the level, also while running
+    let (filter, log_level) = reload::Layer::new(LevelFilter::from_level(level));
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
 
-    let cfg =
-        serde_json::from_str::<config::Config>(include_str!("../config.json")).map_err(|err| {
-            tracing::error!("Could not parse config file");
-            err
-        })?;
+    let cfg = match config::Config::load(std::env::args().skip(1)) {
+        Ok(cfg) => cfg,
+        Err(config::ConfigError::Help) => {
+            print!("{}", config::USAGE);
+            exit(0);
+        }
+        Err(err) => {
+            tracing::error!(message = "Could not load config", %err);
+            exit(2);
+        }
+    };
 
-    let parent_connection = connect_to_parent(&cfg).await.map_err(|err| {
-        tracing::error!(message = "Could not connect to network", %err);
-        tracing::warn!(message = "Server Starting without parent");
-    });
+    let _ = span.enter();
 
-    let mut parent = None;
-    if let Ok(parent_connection) = parent_connection {
-        tracing::info!(message = "Connected to parent");
-        parent = match parent_connection.peer_addr() {
-            Ok(addr) => Some(Client::new(parent_connection, addr)),
-            Err(err) => {
-                tracing::error!(message = "Could not get parent address", %err);
-                tracing::warn!(message = "Server Starting without parent");
-                None
+    let tls = Tls::load(&cfg)
+        .inspect_err(|err| tracing::error!(message = "Could not load TLS certificates", %err))?;
+
+    let mut listeners = Vec::new();
+    let mut listen_addrs = Vec::new();
+    for node in cfg.bind() {
+        let addrs = node.resolve().await.inspect_err(|err| {
+            tracing::error!(message = "Could not resolve bind address", %node, %err);
+        })?;
+        for addr in addrs {
+            if listen_addrs.contains(&addr) {
+                continue;
             }
-        };
+            let listener = listen(addr)
+                .inspect_err(|err| tracing::error!(message = "Could not listen", %addr, %err))?;
+            let local_addr = listener.local_addr().inspect_err(|err| {
+                tracing::error!(message = "Could not get local address", %err);
+            })?;
+            tracing::debug!(message = "Listening on", addr = %local_addr);
+            // port 0 hands out a different port for every listener, the configured address is
+            // what duplicates are recognised by
+            listen_addrs.push(addr);
+            listeners.push((listener, local_addr));
+        }
     }
+    let local_addrs = listeners.iter().map(|(_, a)| *a).collect::<Vec<_>>();
 
-    let addr = format!("127.0.0.1:{}", cfg.port());
-    let _ = span.enter();
-
-    // Check if the address is already in use
-    let addr = std::net::SocketAddr::from_str(&addr).map_err(|err| {
-        tracing::error!(message = "Address is in use alread. Set `ADDR` to a different address", %addr, err = %err);
-        exit(1);
-    }).expect("Should never reach, because of the exit");
+    let parent_connection = connect_to_parent(&cfg, &local_addrs, tls.as_ref())
+        .await
+        .map_err(|err| {
+            tracing::error!(message = "Could not connect to network", %err);
+            tracing::warn!(message = "Server Starting without parent");
+        });
 
-    // let addr = match std::net::SocketAddr::from_str(&addr) {
-    //     Ok(addr) => addr,
-    //     Err(err) => {}
-    // };
+    let parent = parent_connection
+        .ok()
+        .map(|(connection, addr)| Client::new(connection, addr.into(), Protocol::Native));
 
     let cfg = Arc::new(cfg);
     let (tx, rx) = tokio::sync::mpsc::channel(10);
-    let server = crate::server::Server::new(rx, Arc::clone(&cfg), parent).await;
-    server.start_daemon().await;
-
-    let connection = tokio::net::TcpListener::bind(addr).await?;
-    let addr = connection.local_addr().map_err(|err| {
-        tracing::error!(message = "Could not get local address", %err);
-        err
-    })?;
-    tracing::debug!(message = "Listening on", %addr);
+    let server = server::Server::new(rx, Arc::clone(&cfg), parent)
+        .await
+        .with_log_level(log_level, LevelFilter::from_level(level));
+    server.start_daemon(tx.clone()).await;
 
     #[cfg(debug_assertions)]
     log_debug_task();
 
-    // Accept incoming connections
+    if let Some(path) = cfg.unix_socket() {
+        let listener = bind_unix(path, cfg.unix_socket_perm())?;
+        tokio::task::spawn(accept_unix_clients(listener, cfg.protocol(), tx.clone()));
+    }
+
+    let accepting = listeners
+        .into_iter()
+        .map(|(listener, _)| accept_clients(listener, cfg.protocol(), tls.clone(), tx.clone()));
+    futures::future::join_all(accepting).await;
+    Ok(())
+}
+
+/// Binds a TCP listener to `addr`. IPv6 ones only take IPv6, otherwise `::` would also claim the
+/// IPv4 port and `["0.0.0.0", "::"]` couldn't both be bound.
+fn listen(addr: SocketAddr) -> std::io::Result<tokio::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+/// Hands every connection made to `listener` to the server as a new client, after the TLS
+/// handshake if it's configured.
+async fn accept_clients(
+    listener: tokio::net::TcpListener,
+    protocol: Protocol,
+    tls: Option<Tls>,
+    tx: tokio::sync::mpsc::Sender<server::ServerMessages>,
+) {
     loop {
-        if let Ok((stream, addr)) = connection.accept().await {
-            let client = crate::client::Client::new(stream, addr);
-            tx.clone()
-                .send(server::ServerMessages::NewClient(addr, client, tx.clone()))
-                .await
-                .map_err(|err| tracing::error!(message = "Could not send message to server", %err))
-                .unwrap();
+        let Ok((stream, addr)) = listener.accept().await else {
+            continue;
+        };
+        let Some(tls) = tls.clone() else {
+            let client = Client::new(stream, addr.into(), protocol);
+            new_client(&tx, addr.into(), client).await;
+            continue;
+        };
+        // a slow handshake mustn't hold up the connections behind it
+        let tx = tx.clone();
+        tokio::task::spawn(async move {
+            let accepted = match tls.accept(stream).await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    tracing::debug!(message = "TLS handshake failed", %addr, %err);
+                    return;
+                }
+            };
+            let mut client = Client::new(accepted.stream, addr.into(), protocol);
+            if accepted.verified {
+                client = client.with_verified_cert();
+            }
+            new_client(&tx, addr.into(), client).await;
+        });
+    }
+}
+
+async fn new_client(
+    tx: &tokio::sync::mpsc::Sender<server::ServerMessages>,
+    addr: PeerAddr,
+    client: Client,
+) {
+    tx.send(server::ServerMessages::NewClient(addr, client, tx.clone()))
+        .await
+        .map_err(|err| tracing::error!(message = "Could not send message to server", %err))
+        .unwrap();
+}
+
+/// Creates the unix socket at `path`, replacing the one a previous run left behind. A socket
+/// another node still answers on, or anything else already at `path`, is left alone and fails
+/// the bind.
+fn bind_unix(path: &Path, mode: Option<u32>) -> std::io::Result<UnixListener> {
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => {
+                tracing::error!(message = "Socket is in use", path = %path.display());
+                return Err(std::io::ErrorKind::AddrInUse.into());
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {
+                std::fs::remove_file(path)?;
+            }
+            Err(err) => {
+                tracing::error!(message = "Could not check existing socket", path = %path.display(), %err);
+                return Err(err);
+            }
         }
     }
+    let listener = match mode {
+        Some(mode) => bind_unix_with_mode(path, mode),
+        None => UnixListener::bind(path),
+    }
+    .inspect_err(
+        |err| tracing::error!(message = "Could not listen", path = %path.display(), %err),
+    )?;
+    tracing::debug!(message = "Listening on", path = %path.display());
+    Ok(listener)
+}
+
+/// Binds the socket in a directory only we can get into, and moves it to `path` once it has
+/// `mode`. Bound at `path` right away, anyone could connect before the mode was applied.
+fn bind_unix_with_mode(path: &Path, mode: u32) -> std::io::Result<UnixListener> {
+    let name = path.file_name().ok_or(std::io::ErrorKind::InvalidInput)?;
+    let mut dir_name = std::ffi::OsString::from(".");
+    dir_name.push(name);
+    dir_name.push(format!(".{}", std::process::id()));
+    let dir = path.with_file_name(dir_name);
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let tmp = dir.join(name);
+    let bound = UnixListener::bind(&tmp).and_then(|listener| {
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&tmp, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&tmp);
+    let _ = std::fs::remove_dir(&dir);
+    bound
+}
+
+/// Like [`accept_clients`], for the unix socket. Its peers are told apart by a number.
+async fn accept_unix_clients(
+    listener: UnixListener,
+    protocol: Protocol,
+    tx: tokio::sync::mpsc::Sender<server::ServerMessages>,
+) {
+    for n in 0.. {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let addr = PeerAddr::Unix(n);
+        let client = Client::new(stream, addr, protocol);
+        new_client(&tx, addr, client).await;
+    }
+}
+
+/// Whether `target` is one of the addresses we listen on. Listening on every interface, that's
+/// any loopback address with our port.
+fn is_own_addr(target: SocketAddr, listen_addrs: &[SocketAddr]) -> bool {
+    listen_addrs.iter().any(|addr| {
+        addr.port() == target.port()
+            && (addr.ip() == target.ip() || addr.ip().is_unspecified() && target.ip().is_loopback())
+    })
 }
 
-/// logs the number of active tasks every time it changes.
+/// logs the number of active tasks every time it changes, polled once a second so it doesn't
+/// starve the runtime.
 #[cfg(debug_assertions)]
 fn log_debug_task() {
     tokio::spawn(async move {
@@ -145,6 +395,7 @@ fn log_debug_task() {
                 debug!(message = "Active Task", %n);
             }
             last = n;
+            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
         }
     });
 }
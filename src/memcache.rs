@@ -0,0 +1,471 @@
+use std::time::Duration;
+
+use crate::{
+    database::{until_unix, CounterError, Database, StoreMode, StoreResult, MAX_TTL},
+    frame::Frame,
+    message::{Outcome, Replication},
+};
+
+/// Longest key memcached accepts.
+const MAX_KEY_LEN: usize = 250;
+/// memcached reads exptimes above 30 days as absolute unix timestamps instead of seconds from now.
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+/// A request in memcached's text protocol.
+#[derive(Debug)]
+pub enum MemcacheCommand {
+    // set|add|replace|append|prepend KEY FLAGS EXPTIME BYTES [noreply]\r\nDATA
+    // cas KEY FLAGS EXPTIME BYTES CAS_UNIQUE [noreply]\r\nDATA
+    Store {
+        mode: StoreMode,
+        key: String,
+        flags: u32,
+        ttl: Option<Duration>,
+        value: Vec<u8>,
+        noreply: bool,
+    },
+    // get|gets KEY [KEY...]
+    Get {
+        keys: Vec<String>,
+        with_cas: bool,
+    },
+    // delete KEY [noreply]
+    Delete {
+        key: String,
+        noreply: bool,
+    },
+    // incr|decr KEY DELTA [noreply]
+    Incr {
+        key: String,
+        delta: u64,
+        decr: bool,
+        noreply: bool,
+    },
+    // touch KEY EXPTIME [noreply]
+    Touch {
+        key: String,
+        ttl: Option<Duration>,
+        noreply: bool,
+    },
+    // flush_all [DELAY] [noreply]
+    FlushAll {
+        delay: u64,
+        noreply: bool,
+    },
+    // stats
+    Stats,
+    // version
+    Version,
+}
+
+#[derive(Debug)]
+pub enum MemcacheError {
+    /// Not a command we know, answered with a bare `ERROR`
+    Unknown,
+    /// A known command with bad arguments, answered with `CLIENT_ERROR <msg>`
+    Client(&'static str),
+}
+
+impl MemcacheError {
+    pub fn reply(&self) -> Vec<u8> {
+        match self {
+            MemcacheError::Unknown => b"ERROR\r\n".to_vec(),
+            MemcacheError::Client(msg) => format!("CLIENT_ERROR {}\r\n", msg).into_bytes(),
+        }
+    }
+}
+
+const BAD_FORMAT: MemcacheError = MemcacheError::Client("bad command line format");
+
+impl MemcacheCommand {
+    /// The `BYTES` argument of the storage commands, see [`crate::frame::FrameReader`].
+    pub fn payload_len(line: &str) -> Option<usize> {
+        let mut s = line.split_whitespace();
+        match s.next()? {
+            "set" | "add" | "replace" | "append" | "prepend" | "cas" => s.nth(3)?.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn from_frame(frame: &Frame) -> Result<Self, MemcacheError> {
+        let mut s = frame
+            .text()
+            .ok_or(MemcacheError::Unknown)?
+            .split_whitespace();
+        let cmd = s.next().ok_or(MemcacheError::Unknown)?;
+        let args = s.collect::<Vec<_>>();
+        let noreply = |idx: usize| args.get(idx) == Some(&"noreply");
+
+        match cmd {
+            "set" | "add" | "replace" | "append" | "prepend" | "cas" => {
+                let value = frame
+                    .payload
+                    .clone()
+                    .ok_or(MemcacheError::Client("bad data chunk"))?;
+                let key = parse_key(args.first())?;
+                let flags = parse_num(args.get(1))?;
+                let ttl = exptime_to_ttl(parse_num(args.get(2))?)?;
+
+                let (mode, noreply) = match cmd {
+                    "set" => (StoreMode::Set, noreply(4)),
+                    "add" => (StoreMode::Add, noreply(4)),
+                    "replace" => (StoreMode::Replace, noreply(4)),
+                    "append" => (StoreMode::Append, noreply(4)),
+                    "prepend" => (StoreMode::Prepend, noreply(4)),
+                    _ => (StoreMode::Cas(parse_num(args.get(4))?), noreply(5)),
+                };
+
+                Ok(MemcacheCommand::Store {
+                    mode,
+                    key,
+                    flags,
+                    ttl,
+                    value,
+                    noreply,
+                })
+            }
+            "get" | "gets" => {
+                if args.is_empty() {
+                    return Err(MemcacheError::Unknown);
+                }
+                let keys = args
+                    .iter()
+                    .map(|k| parse_key(Some(k)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(MemcacheCommand::Get {
+                    keys,
+                    with_cas: cmd == "gets",
+                })
+            }
+            "delete" => {
+                // old clients still send the long gone `delete KEY 0`
+                let noreply = args.len() > 1 && args.last() == Some(&"noreply");
+                Ok(MemcacheCommand::Delete {
+                    key: parse_key(args.first())?,
+                    noreply,
+                })
+            }
+            "incr" | "decr" => Ok(MemcacheCommand::Incr {
+                key: parse_key(args.first())?,
+                delta: parse_num(args.get(1))
+                    .map_err(|_| MemcacheError::Client("invalid numeric delta argument"))?,
+                decr: cmd == "decr",
+                noreply: noreply(2),
+            }),
+            "touch" => Ok(MemcacheCommand::Touch {
+                key: parse_key(args.first())?,
+                ttl: exptime_to_ttl(parse_num(args.get(1))?)?,
+                noreply: noreply(2),
+            }),
+            "flush_all" => {
+                let delay = match args.first() {
+                    Some(&"noreply") | None => 0,
+                    Some(delay) => delay.parse().map_err(|_| BAD_FORMAT)?,
+                };
+                Ok(MemcacheCommand::FlushAll {
+                    delay,
+                    noreply: args.last() == Some(&"noreply"),
+                })
+            }
+            "stats" => Ok(MemcacheCommand::Stats),
+            "version" => Ok(MemcacheCommand::Version),
+            _ => Err(MemcacheError::Unknown),
+        }
+    }
+
+    pub async fn execute(self, db: &Database) -> Outcome {
+        let (reply, replicate, noreply) = match self {
+            MemcacheCommand::Store {
+                mode,
+                key,
+                flags,
+                ttl,
+                value,
+                noreply,
+            } => {
+                let res = db.store(key.clone(), value, flags, ttl, mode).await;
+                let reply = match res {
+                    StoreResult::Stored => "STORED",
+                    StoreResult::NotStored => "NOT_STORED",
+                    StoreResult::Exists => "EXISTS",
+                    StoreResult::NotFound => "NOT_FOUND",
+                    StoreResult::OutOfMemory => "SERVER_ERROR out of memory storing object",
+                };
+                let replicate = (res == StoreResult::Stored).then_some(Replication::Set(key));
+                (format!("{}\r\n", reply).into_bytes(), replicate, noreply)
+            }
+            MemcacheCommand::Get { keys, with_cas } => {
+                let mut reply = Vec::new();
+                for key in keys {
+                    let Some(data) = db.get_or_remove(key.clone()).await else {
+                        continue;
+                    };
+                    let Some(value) = data.inner() else {
+                        continue;
+                    };
+
+                    let mut header = format!("VALUE {} {} {}", key, data.flags(), value.len());
+                    if with_cas {
+                        header.push_str(&format!(" {}", data.cas()));
+                    }
+                    reply.extend(header.into_bytes());
+                    reply.extend_from_slice(b"\r\n");
+                    reply.extend(value);
+                    reply.extend_from_slice(b"\r\n");
+                }
+                reply.extend_from_slice(b"END\r\n");
+                (reply, None, false)
+            }
+            MemcacheCommand::Delete { key, noreply } => {
+                let reply = match db.remove(&key).await {
+                    true => b"DELETED\r\n".to_vec(),
+                    false => b"NOT_FOUND\r\n".to_vec(),
+                };
+                // the other nodes may still hold a copy we never saw, so always pass it on
+                (reply, Some(Replication::Remove(key)), noreply)
+            }
+            MemcacheCommand::Incr {
+                key,
+                delta,
+                decr,
+                noreply,
+            } => match db.incr(&key, delta, decr).await {
+                Ok(n) => (
+                    format!("{}\r\n", n).into_bytes(),
+                    Some(Replication::Set(key)),
+                    noreply,
+                ),
+                Err(CounterError::NotFound) => (b"NOT_FOUND\r\n".to_vec(), None, noreply),
+                Err(CounterError::OutOfMemory) => {
+                    (b"SERVER_ERROR out of memory\r\n".to_vec(), None, noreply)
+                }
+                Err(CounterError::NotANumber | CounterError::Overflow) => (
+                    b"CLIENT_ERROR cannot increment or decrement non-numeric value\r\n".to_vec(),
+                    None,
+                    noreply,
+                ),
+            },
+            MemcacheCommand::Touch { key, ttl, noreply } => match db.touch(&key, ttl).await {
+                true => (
+                    b"TOUCHED\r\n".to_vec(),
+                    Some(Replication::Set(key)),
+                    noreply,
+                ),
+                false => (b"NOT_FOUND\r\n".to_vec(), None, noreply),
+            },
+            MemcacheCommand::FlushAll { delay, noreply } => {
+                // the server does the flush, so it's logged in order with the writes around it
+                let delay = (delay > 0).then(|| Duration::from_secs(delay));
+                (b"OK\r\n".to_vec(), Some(Replication::Flush(delay)), noreply)
+            }
+            MemcacheCommand::Stats => {
+                let mut reply = String::new();
+                for (name, value) in db.stats().await.fields() {
+                    reply.push_str(&format!("STAT {} {}\r\n", name, value));
+                }
+                reply.push_str("END\r\n");
+                (reply.into_bytes(), None, false)
+            }
+            MemcacheCommand::Version => (
+                format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes(),
+                None,
+                false,
+            ),
+        };
+
+        Outcome {
+            reply: if noreply { Vec::new() } else { reply },
+            replicate: replicate.into_iter().collect(),
+        }
+    }
+}
+
+fn parse_key(key: Option<&&str>) -> Result<String, MemcacheError> {
+    let key = key.ok_or(BAD_FORMAT)?;
+    if key.len() > MAX_KEY_LEN || key.chars().any(|c| c.is_control()) {
+        return Err(BAD_FORMAT);
+    }
+    Ok(key.to_string())
+}
+
+fn parse_num<T: std::str::FromStr>(arg: Option<&&str>) -> Result<T, MemcacheError> {
+    arg.ok_or(BAD_FORMAT)?.parse().map_err(|_| BAD_FORMAT)
+}
+
+/// 0 never expires, negative is already expired, anything above 30 days is a unix timestamp.
+/// Timestamps past [`MAX_TTL`] are refused.
+fn exptime_to_ttl(exptime: i64) -> Result<Option<Duration>, MemcacheError> {
+    let ttl = match exptime {
+        0 => return Ok(None),
+        e if e < 0 => Duration::ZERO,
+        e if e > MAX_RELATIVE_EXPTIME => {
+            until_unix(Duration::from_secs(e as u64)).ok_or(BAD_FORMAT)?
+        }
+        e => Duration::from_secs(e as u64),
+    };
+    match ttl > MAX_TTL {
+        true => Err(BAD_FORMAT),
+        false => Ok(Some(ttl)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str, payload: Option<&[u8]>) -> Result<MemcacheCommand, MemcacheError> {
+        MemcacheCommand::from_frame(&Frame {
+            line: line.as_bytes().to_vec(),
+            payload: payload.map(<[u8]>::to_vec),
+        })
+    }
+
+    fn client_error(res: Result<MemcacheCommand, MemcacheError>) -> &'static str {
+        match res {
+            Err(MemcacheError::Client(msg)) => msg,
+            other => panic!("expected a client error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn payload_len_is_the_bytes_argument() {
+        assert_eq!(MemcacheCommand::payload_len("set k 0 0 5"), Some(5));
+        assert_eq!(MemcacheCommand::payload_len("cas k 0 0 5 99"), Some(5));
+        assert_eq!(
+            MemcacheCommand::payload_len("append k 0 0 5 noreply"),
+            Some(5)
+        );
+        assert_eq!(MemcacheCommand::payload_len("set k 0 0"), None);
+        assert_eq!(MemcacheCommand::payload_len("get k"), None);
+    }
+
+    #[test]
+    fn store_commands() {
+        match parse("set k 7 0 3", Some(b"abc")) {
+            Ok(MemcacheCommand::Store {
+                mode: StoreMode::Set,
+                key,
+                flags: 7,
+                ttl: None,
+                value,
+                noreply: false,
+            }) => assert_eq!((key.as_str(), &value[..]), ("k", &b"abc"[..])),
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(
+            parse("cas k 0 60 3 42 noreply", Some(b"abc")),
+            Ok(MemcacheCommand::Store {
+                mode: StoreMode::Cas(42),
+                ttl: Some(ttl),
+                noreply: true,
+                ..
+            }) if ttl == Duration::from_secs(60)
+        ));
+        // noreply sits after the cas token, in its place it's not a number
+        assert_eq!(
+            client_error(parse("cas k 0 0 3 noreply", Some(b"abc"))),
+            "bad command line format"
+        );
+        assert_eq!(client_error(parse("set k 0 0 3", None)), "bad data chunk");
+        assert!(parse("set k x 0 3", Some(b"abc")).is_err());
+    }
+
+    #[test]
+    fn exptimes() {
+        let ttl = |exptime: &str| match parse(&format!("touch k {}", exptime), None) {
+            Ok(MemcacheCommand::Touch { ttl, .. }) => Ok(ttl),
+            Err(err) => Err(err),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(ttl("0").unwrap(), None);
+        assert_eq!(ttl("-1").unwrap(), Some(Duration::ZERO));
+        assert_eq!(ttl("100").unwrap(), Some(Duration::from_secs(100)));
+        // above 30 days it's a unix timestamp, this one long gone
+        assert_eq!(ttl("2592001").unwrap(), Some(Duration::ZERO));
+        assert!(ttl(&i64::MAX.to_string()).is_err());
+    }
+
+    #[test]
+    fn keys() {
+        assert!(matches!(
+            parse("get a b c", None),
+            Ok(MemcacheCommand::Get { keys, with_cas: false }) if keys == ["a", "b", "c"]
+        ));
+        assert!(matches!(
+            parse("gets a", None),
+            Ok(MemcacheCommand::Get { with_cas: true, .. })
+        ));
+        assert!(matches!(parse("get", None), Err(MemcacheError::Unknown)));
+        assert!(parse(&format!("get {}", "k".repeat(MAX_KEY_LEN)), None).is_ok());
+        assert!(parse(&format!("get {}", "k".repeat(MAX_KEY_LEN + 1)), None).is_err());
+    }
+
+    #[test]
+    fn noreply_positions() {
+        let delete = |line| match parse(line, None) {
+            Ok(MemcacheCommand::Delete { noreply, .. }) => noreply,
+            other => panic!("{:?}", other),
+        };
+        assert!(!delete("delete k"));
+        assert!(delete("delete k noreply"));
+        assert!(!delete("delete k 0"));
+        assert!(delete("delete k 0 noreply"));
+
+        assert!(matches!(
+            parse("incr k 5 noreply", None),
+            Ok(MemcacheCommand::Incr {
+                delta: 5,
+                decr: false,
+                noreply: true,
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse("decr k 1", None),
+            Ok(MemcacheCommand::Incr {
+                decr: true,
+                noreply: false,
+                ..
+            })
+        ));
+        assert_eq!(
+            client_error(parse("incr k -1", None)),
+            "invalid numeric delta argument"
+        );
+
+        assert!(matches!(
+            parse("flush_all", None),
+            Ok(MemcacheCommand::FlushAll {
+                delay: 0,
+                noreply: false
+            })
+        ));
+        assert!(matches!(
+            parse("flush_all noreply", None),
+            Ok(MemcacheCommand::FlushAll {
+                delay: 0,
+                noreply: true
+            })
+        ));
+        assert!(matches!(
+            parse("flush_all 10 noreply", None),
+            Ok(MemcacheCommand::FlushAll {
+                delay: 10,
+                noreply: true
+            })
+        ));
+    }
+
+    #[test]
+    fn unknown_commands() {
+        assert!(matches!(
+            parse("frobnicate", None),
+            Err(MemcacheError::Unknown)
+        ));
+        assert_eq!(MemcacheError::Unknown.reply(), b"ERROR\r\n");
+        assert_eq!(
+            BAD_FORMAT.reply(),
+            b"CLIENT_ERROR bad command line format\r\n"
+        );
+    }
+}
@@ -1,46 +1,140 @@
 use std::{net::SocketAddr, str::FromStr, time::Duration};
 
+use crate::{
+    database::{until_unix, StoreMode, MAX_TTL},
+    frame::Frame,
+};
+
+/// What the server does after running a command of one of the client protocols: answer the
+/// client, and for writes, push the key out to the other nodes.
 #[derive(Debug)]
-pub enum JoinMessage {
-    NodeJoin,
-    Client,
+pub struct Outcome {
+    /// Empty when the client asked for no reply
+    pub reply: Vec<u8>,
+    pub replicate: Vec<Replication>,
 }
 
-impl FromStr for JoinMessage {
-    type Err = ();
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let s = s.trim();
-        tracing::debug!(s);
-        let open = s.split_once(' ');
-
-        let (action, rest) = match open {
-            Some(s) => s,
-            None => return Err(()),
-        };
-        tracing::debug!(action);
-        tracing::debug!(rest);
-
-        if action.eq_ignore_ascii_case("JOINNODE") {
-            return Ok(JoinMessage::NodeJoin);
-        }
-        if action.eq_ignore_ascii_case("CLIENT") {
-            return Ok(JoinMessage::Client);
-        }
-        Err(())
-    }
+/// A change the other nodes have to apply to stay in sync with us.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Replication {
+    /// Send them whatever we now hold for the key
+    Set(String),
+    Remove(String),
+    /// Drop every key, after the delay if there is one. The other nodes flush when we do
+    Flush(Option<Duration>),
 }
 
+/// Wherever the grammar below says DURATION it takes seconds (`10` or `10s`), milliseconds
+/// (`1500ms`) or, prefixed with `@`, an absolute unix time in either unit (`@1700000000`,
+/// `@1700000000000ms`). A time in the past expires right away.
 #[derive(Debug)]
 pub enum ClientMessage {
-    SetKey { key: String, dur: Duration }, // SET KEY_NAME DURATION
-    SetValue { key: String, value: String },
-    GetValue { key: String }, // GET KEY_NAME
-    JoinNode { addr: SocketAddr },
-    Defered { addr: SocketAddr },
+    // SET KEY_NAME [DURATION] [NX|XX]
+    // CAS KEY_NAME DURATION TOKEN
+    SetKey {
+        key: String,
+        dur: Duration,
+        mode: StoreMode,
+    },
+    // KEY_NAME:VALUE, or any line while a SET is waiting for its value
+    SetValue {
+        key: String,
+        value: Vec<u8>,
+    },
+    // SET KEY_NAME DURATION VALUE_LEN [NX|XX]\nVALUE
+    // CAS KEY_NAME DURATION TOKEN VALUE_LEN\nVALUE
+    Set {
+        key: String,
+        dur: Duration,
+        mode: StoreMode,
+        value: Vec<u8>,
+    },
+    // GET KEY_NAME
+    GetValue {
+        key: String,
+    },
+    // GETS KEY_NAME, answered with `TOKEN VALUE_LEN\nVALUE`; the token goes into CAS
+    GetWithToken {
+        key: String,
+    },
+    // MGET KEY_NAME [KEY_NAME...], answered with `VALUE KEY_NAME VALUE_LEN\nVALUE` per hit and
+    // `MISS KEY_NAME` per miss, then `END`
+    GetMany {
+        keys: Vec<String>,
+    },
+    // MSET KEY_NAME VALUE [KEY_NAME VALUE...] [DURATION], answered with `STORED COUNT` once every
+    // key is written, or an error if they don't all fit and none was
+    SetMany {
+        pairs: Vec<(String, Vec<u8>)>,
+        dur: Duration,
+    },
+    // INCR|DECR KEY_NAME [DELTA] [INIT VALUE] [TTL DURATION], a missing key starts at INIT (0) and
+    // never expires unless TTL is given
+    Counter {
+        key: String,
+        delta: i64,
+        init: i64,
+        ttl: Option<Duration>,
+    },
+    // TOUCH KEY_NAME, restarts the countdown of the key's DURATION
+    Touch {
+        key: String,
+    },
+    // EXPIRE KEY_NAME DURATION, replaces the key's DURATION counting from now
+    Expire {
+        key: String,
+        dur: Duration,
+    },
+    // PERSIST KEY_NAME, the key never expires
+    Persist {
+        key: String,
+    },
+    // TTL|PTTL KEY_NAME, answered with the seconds (milliseconds) left, -1 if it never expires
+    // and -2 if it's missing
+    Ttl {
+        key: String,
+        millis: bool,
+    },
+    // SAVE|BGSAVE, writes a snapshot to the configured path, BGSAVE without waiting for it
+    Save {
+        background: bool,
+    },
+    // STATS, answered with a `STAT NAME VALUE` line per counter and `END`
+    Stats,
+    // RELOAD, rereads the configuration like SIGHUP does. Answered with a `CHANGED SETTING` line
+    // per setting now in effect, a `RESTART SETTING` line per changed one that only applies after
+    // a restart, an `IGNORED SETTING` line per changed one that does nothing, and `END`
+    Reload,
+    // DEL KEY_NAME [KEY_NAME...]
+    Delete {
+        keys: Vec<String>,
+    },
+    // JOIN LISTEN_ADDR
+    JoinNode {
+        addr: SocketAddr,
+    },
+    // DEFERED NODE_ADDR
+    Defered {
+        addr: SocketAddr,
+    },
+    // SYNC KEY_LEN TTL_MILLIS FLAGS VALUE_LEN\nKEY_NAMEVALUE, a TTL of -1 never expires. Keys
+    // are sent by length since any string is a valid key on the native and RESP ports
+    Replicate {
+        key: String,
+        ttl: Option<Duration>,
+        flags: u32,
+        value: Vec<u8>,
+    },
+    // SYNCDEL KEY_LEN\nKEY_NAME
+    ReplicateRemove {
+        key: String,
+    },
+    // SYNCFLUSH, drops every key
+    ReplicateFlush,
 }
 
-const ONE_HOUR: u64 = 1 << 4;
+/// How long a write that doesn't give a DURATION lives.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(16);
 
 impl FromStr for ClientMessage {
     type Err = ();
@@ -52,26 +146,76 @@ impl FromStr for ClientMessage {
             "GET" => Ok(ClientMessage::GetValue {
                 key: s.next().ok_or(())?.to_string(),
             }),
+            "GETS" => Ok(ClientMessage::GetWithToken {
+                key: s.next().ok_or(())?.to_string(),
+            }),
             "SET" => {
-                let key = s.next().ok_or(())?.to_string();
-
-                let dur = {
-                    let s = s.next();
-                    if let Some(s) = s {
-                        let sec = s.parse::<u64>();
-
-                        if sec.is_err() {
-                            return Err(());
-                        }
-
-                        Duration::from_secs(sec.expect("unreachable"))
-                    } else {
-                        Duration::from_secs(ONE_HOUR)
-                    }
+                let (key, dur, mode, len) = Self::parse_set(s)?;
+                if len.is_some() {
+                    // the value never arrived, the frame reader only skips it when it's too long
+                    return Err(());
+                }
+                Ok(ClientMessage::SetKey { key, dur, mode })
+            }
+            "CAS" => {
+                let (key, dur, mode, len) = Self::parse_cas(s)?;
+                if len.is_some() {
+                    return Err(());
+                }
+                Ok(ClientMessage::SetKey { key, dur, mode })
+            }
+            "MGET" => {
+                let keys = s.map(str::to_string).collect::<Vec<_>>();
+                if keys.is_empty() {
+                    return Err(());
+                }
+                Ok(ClientMessage::GetMany { keys })
+            }
+            "MSET" => {
+                let mut args = s.collect::<Vec<_>>();
+                let dur = match args.len() % 2 {
+                    1 => parse_duration(args.pop().expect("odd length"))?,
+                    _ => DEFAULT_TTL,
                 };
-
-                Ok(ClientMessage::SetKey { key, dur })
+                if args.is_empty() {
+                    return Err(());
+                }
+                let pairs = args
+                    .chunks(2)
+                    .map(|kv| (kv[0].to_string(), kv[1].as_bytes().to_vec()))
+                    .collect();
+                Ok(ClientMessage::SetMany { pairs, dur })
             }
+            cmd @ ("SAVE" | "BGSAVE") => Ok(ClientMessage::Save {
+                background: cmd == "BGSAVE",
+            }),
+            "STATS" => Ok(ClientMessage::Stats),
+            "RELOAD" => Ok(ClientMessage::Reload),
+            "TOUCH" => Ok(ClientMessage::Touch {
+                key: s.next().ok_or(())?.to_string(),
+            }),
+            "EXPIRE" => {
+                let key = s.next().ok_or(())?.to_string();
+                let dur = parse_duration(s.next().ok_or(())?)?;
+                Ok(ClientMessage::Expire { key, dur })
+            }
+            "PERSIST" => Ok(ClientMessage::Persist {
+                key: s.next().ok_or(())?.to_string(),
+            }),
+            cmd @ ("TTL" | "PTTL") => Ok(ClientMessage::Ttl {
+                key: s.next().ok_or(())?.to_string(),
+                millis: cmd == "PTTL",
+            }),
+            "INCR" => Self::parse_counter(s, false),
+            "DECR" => Self::parse_counter(s, true),
+            "DEL" => {
+                let keys = s.map(str::to_string).collect::<Vec<_>>();
+                if keys.is_empty() {
+                    return Err(());
+                }
+                Ok(ClientMessage::Delete { keys })
+            }
+            "SYNCFLUSH" => Ok(ClientMessage::ReplicateFlush),
             "DEFERED" => {
                 let addr = s.next().ok_or(())?;
                 let addr = addr.parse::<SocketAddr>().map_err(|_| ())?;
@@ -82,15 +226,432 @@ impl FromStr for ClientMessage {
                 let addr = addr.parse::<SocketAddr>().map_err(|_| ())?;
                 Ok(ClientMessage::JoinNode { addr })
             }
-            _ => {
-                // parse "Hello:jhsjdh"
-                // where "Hello" is the key and "jhsjdh" is the value
-                let (key, value) = input
-                    .split_once(':')
-                    .ok_or(())
-                    .map(|(f, u)| (f.to_string(), u.to_string()))?;
-                Ok(ClientMessage::SetValue { key, value })
+            _ => Self::parse_set_value(input.as_bytes()),
+        }
+    }
+}
+
+impl ClientMessage {
+    /// How many bytes of value follow `line`, for the commands that carry one. The frame reader
+    /// uses this to switch from reading lines to reading the value.
+    pub fn payload_len(line: &str) -> Option<usize> {
+        let mut s = line.split_whitespace();
+        match s.next()? {
+            // the byte count is optional, a third argument may also be the NX/XX flag
+            "SET" => s.nth(2)?.parse().ok(),
+            "CAS" => s.nth(3)?.parse().ok(),
+            // the key comes first in the payload, then the value
+            "SYNC" => {
+                let key_len = s.next()?.parse::<usize>().ok()?;
+                key_len.checked_add(s.nth(2)?.parse().ok()?)
+            }
+            "SYNCDEL" => s.next()?.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Parses a framed request. Commands that announced a byte count take their value from the
+    /// frame payload, everything else is parsed from the line alone.
+    pub fn from_frame(frame: &Frame) -> Result<Self, ()> {
+        let Some(ref value) = frame.payload else {
+            return match frame.text() {
+                Some(line) => line.parse(),
+                None => Self::parse_set_value(&frame.line),
+            };
+        };
+        let mut value = value.to_owned();
+
+        let mut s = frame.text().ok_or(())?.split_whitespace();
+        match s.next().ok_or(())? {
+            "SET" => {
+                let (key, dur, mode, _) = Self::parse_set(s)?;
+                Ok(ClientMessage::Set {
+                    key,
+                    dur,
+                    mode,
+                    value,
+                })
+            }
+            "CAS" => {
+                let (key, dur, mode, _) = Self::parse_cas(s)?;
+                Ok(ClientMessage::Set {
+                    key,
+                    dur,
+                    mode,
+                    value,
+                })
             }
+            "SYNC" => {
+                let key_len = s.next().ok_or(())?.parse::<usize>().map_err(|_| ())?;
+                let key = split_key(&mut value, key_len)?;
+                let ttl = s.next().ok_or(())?.parse::<i64>().map_err(|_| ())?;
+                let flags = s.next().ok_or(())?.parse::<u32>().map_err(|_| ())?;
+                Ok(ClientMessage::Replicate {
+                    key,
+                    ttl: u64::try_from(ttl).ok().map(Duration::from_millis),
+                    flags,
+                    value,
+                })
+            }
+            "SYNCDEL" => {
+                let key = String::from_utf8(value).map_err(|_| ())?;
+                Ok(ClientMessage::ReplicateRemove { key })
+            }
+            _ => Err(()),
+        }
+    }
+
+    /// Encodes a value the way [`ClientMessage::Replicate`] is parsed on the other end of a node
+    /// link. `ttl` is the time the value has left to live, so every copy expires together.
+    pub fn encode_replication(
+        key: &str,
+        value: &[u8],
+        ttl: Option<Duration>,
+        flags: u32,
+    ) -> Vec<u8> {
+        let ttl = ttl.map_or(-1, |ttl| ttl.as_millis() as i64);
+        let mut msg = format!("SYNC {} {} {} {}\n", key.len(), ttl, flags, value.len());
+        msg.push_str(key);
+        let mut msg = msg.into_bytes();
+        msg.extend_from_slice(value);
+        msg.push(b'\n');
+        msg
+    }
+
+    pub fn encode_replicate_remove(key: &str) -> Vec<u8> {
+        format!("SYNCDEL {}\n{}\n", key.len(), key).into_bytes()
+    }
+
+    pub fn encode_replicate_flush() -> Vec<u8> {
+        b"SYNCFLUSH\n".to_vec()
+    }
+
+    /// Parses `KEY_NAME [DURATION] [VALUE_LEN] [NX|XX]`, the arguments of SET.
+    fn parse_set<'a>(
+        mut s: impl Iterator<Item = &'a str>,
+    ) -> Result<(String, Duration, StoreMode, Option<usize>), ()> {
+        let key = s.next().ok_or(())?.to_string();
+        let mut args = s.collect::<Vec<_>>();
+
+        let mode = match args.last() {
+            Some(&"NX") => StoreMode::Add,
+            Some(&"XX") => StoreMode::Replace,
+            _ => StoreMode::Set,
+        };
+        if mode != StoreMode::Set {
+            args.pop();
         }
+
+        let (dur, len) = match args[..] {
+            [] => (DEFAULT_TTL, None),
+            [dur] => (parse_duration(dur)?, None),
+            [dur, len] => (parse_duration(dur)?, Some(len.parse().map_err(|_| ())?)),
+            _ => return Err(()),
+        };
+        Ok((key, dur, mode, len))
+    }
+
+    /// Parses `KEY_NAME DURATION TOKEN [VALUE_LEN]`, the arguments of CAS.
+    fn parse_cas<'a>(
+        mut s: impl Iterator<Item = &'a str>,
+    ) -> Result<(String, Duration, StoreMode, Option<usize>), ()> {
+        let key = s.next().ok_or(())?.to_string();
+        let dur = parse_duration(s.next().ok_or(())?)?;
+        let token = s.next().ok_or(())?.parse().map_err(|_| ())?;
+        let len = match s.next() {
+            Some(len) => Some(len.parse().map_err(|_| ())?),
+            None => None,
+        };
+        if s.next().is_some() {
+            return Err(());
+        }
+        Ok((key, dur, StoreMode::Cas(token), len))
+    }
+
+    /// Parses `KEY_NAME [DELTA] [INIT VALUE] [TTL DURATION]`, the arguments of INCR and DECR.
+    fn parse_counter<'a>(mut s: impl Iterator<Item = &'a str>, decr: bool) -> Result<Self, ()> {
+        let key = s.next().ok_or(())?.to_string();
+        let mut s = s.peekable();
+
+        let mut delta = 1i64;
+        if let Some(n) = s.next_if(|arg| !matches!(*arg, "INIT" | "TTL")) {
+            delta = n.parse().map_err(|_| ())?;
+        }
+        if decr {
+            delta = delta.checked_neg().ok_or(())?;
+        }
+
+        let (mut init, mut ttl) = (None, None);
+        while let Some(opt) = s.next() {
+            let arg = s.next().ok_or(())?;
+            match opt {
+                "INIT" if init.is_none() => init = Some(arg.parse().map_err(|_| ())?),
+                "TTL" if ttl.is_none() => ttl = Some(parse_duration(arg)?),
+                _ => return Err(()),
+            }
+        }
+
+        Ok(ClientMessage::Counter {
+            key,
+            delta,
+            init: init.unwrap_or(0),
+            ttl,
+        })
+    }
+
+    /// parse "Hello:jhsjdh"
+    /// where "Hello" is the key and "jhsjdh" is the value, which may be any bytes
+    fn parse_set_value(line: &[u8]) -> Result<Self, ()> {
+        let split = line.iter().position(|b| *b == b':').ok_or(())?;
+        let key = std::str::from_utf8(&line[..split]).map_err(|_| ())?;
+        Ok(ClientMessage::SetValue {
+            key: key.to_string(),
+            value: line[split + 1..].to_vec(),
+        })
+    }
+}
+
+/// Parses a DURATION, see [`ClientMessage`]. Anything longer than [`MAX_TTL`] is refused.
+fn parse_duration(s: &str) -> Result<Duration, ()> {
+    let (absolute, s) = match s.strip_prefix('@') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let dur = match s.strip_suffix("ms") {
+        Some(ms) => Duration::from_millis(ms.parse().map_err(|_| ())?),
+        None => Duration::from_secs(s.strip_suffix('s').unwrap_or(s).parse().map_err(|_| ())?),
+    };
+    let dur = match absolute {
+        true => until_unix(dur).ok_or(())?,
+        false => dur,
+    };
+    match dur > MAX_TTL {
+        true => Err(()),
+        false => Ok(dur),
+    }
+}
+
+/// Takes the `len` bytes of key off the front of a `SYNC` payload, leaving the value.
+fn split_key(payload: &mut Vec<u8>, len: usize) -> Result<String, ()> {
+    if len > payload.len() {
+        return Err(());
+    }
+    let value = payload.split_off(len);
+    let key = std::mem::replace(payload, value);
+    String::from_utf8(key).map_err(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Protocol,
+        frame::{FrameReader, Request},
+    };
+
+    fn parse(line: &str) -> Result<ClientMessage, ()> {
+        line.parse()
+    }
+
+    fn frame(line: &str, payload: &[u8]) -> Frame {
+        Frame {
+            line: line.as_bytes().to_vec(),
+            payload: Some(payload.to_vec()),
+        }
+    }
+
+    /// Reads `input` the way a node link does.
+    async fn link_messages(input: &[u8]) -> Vec<ClientMessage> {
+        let mut reader = FrameReader::new(input, Protocol::Native);
+        let mut messages = Vec::new();
+        while let Some(request) = reader.next_frame().await.unwrap() {
+            let Request::Text(frame) = request else {
+                panic!("expected a text frame");
+            };
+            messages.push(ClientMessage::from_frame(&frame).unwrap());
+        }
+        messages
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("10"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("10s"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("1500ms"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("@1"), Ok(Duration::ZERO));
+        assert_eq!(parse_duration("@1000ms"), Ok(Duration::ZERO));
+        assert!(parse_duration("@4000000000").unwrap() > Duration::ZERO);
+
+        assert_eq!(parse_duration(""), Err(()));
+        assert_eq!(parse_duration("-1"), Err(()));
+        assert_eq!(parse_duration("10m"), Err(()));
+        assert_eq!(parse_duration("ms"), Err(()));
+    }
+
+    #[test]
+    fn durations_past_max_ttl() {
+        let max = MAX_TTL.as_secs();
+        assert_eq!(parse_duration(&max.to_string()), Ok(MAX_TTL));
+        assert_eq!(parse_duration(&(max + 1).to_string()), Err(()));
+        assert_eq!(parse_duration(&u64::MAX.to_string()), Err(()));
+        assert_eq!(parse_duration(&format!("@{}", u64::MAX)), Err(()));
+        assert_eq!(parse_duration(&format!("@{}ms", u64::MAX)), Err(()));
+    }
+
+    #[test]
+    fn set_modes() {
+        let set = |line| match parse(line) {
+            Ok(ClientMessage::SetKey { dur, mode, .. }) => Ok((dur, mode)),
+            Err(()) => Err(()),
+            other => panic!("{:?}", other),
+        };
+        let secs = Duration::from_secs;
+        assert_eq!(set("SET k"), Ok((DEFAULT_TTL, StoreMode::Set)));
+        assert_eq!(set("SET k NX"), Ok((DEFAULT_TTL, StoreMode::Add)));
+        assert_eq!(set("SET k 10 XX"), Ok((secs(10), StoreMode::Replace)));
+        assert_eq!(set("SET k NX XX"), Err(()));
+        assert_eq!(set("SET k 10 XX NX"), Err(()));
+        assert_eq!(set("SET k 10 nx"), Err(()));
+        assert_eq!(set("SET"), Err(()));
+        // a byte count without its value
+        assert_eq!(set("SET k 10 3"), Err(()));
+    }
+
+    #[test]
+    fn values_come_from_the_payload() {
+        assert_eq!(ClientMessage::payload_len("SET k 10 3"), Some(3));
+        assert_eq!(ClientMessage::payload_len("SET k 10 3 NX"), Some(3));
+        assert_eq!(ClientMessage::payload_len("SET k 10 NX"), None);
+        assert_eq!(ClientMessage::payload_len("CAS k 10 7 3"), Some(3));
+        assert_eq!(ClientMessage::payload_len("GET k"), None);
+
+        match ClientMessage::from_frame(&frame("SET k 10 3 NX", b"a\nb")) {
+            Ok(ClientMessage::Set {
+                key,
+                dur,
+                mode: StoreMode::Add,
+                value,
+            }) => {
+                assert_eq!((key.as_str(), dur), ("k", Duration::from_secs(10)));
+                assert_eq!(value, b"a\nb");
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(
+            ClientMessage::from_frame(&frame("CAS k 10 7 3", b"abc")),
+            Ok(ClientMessage::Set {
+                mode: StoreMode::Cas(7),
+                ..
+            })
+        ));
+        assert!(ClientMessage::from_frame(&frame("GET k 3", b"abc")).is_err());
+    }
+
+    #[test]
+    fn key_value_lines() {
+        match parse("greeting:hello: world") {
+            Ok(ClientMessage::SetValue { key, value }) => {
+                assert_eq!(key, "greeting");
+                assert_eq!(value, b"hello: world");
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(parse("no separator").is_err());
+    }
+
+    #[test]
+    fn counters() {
+        let counter = |line| match parse(line) {
+            Ok(ClientMessage::Counter {
+                delta, init, ttl, ..
+            }) => Ok((delta, init, ttl)),
+            Err(()) => Err(()),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(counter("INCR k"), Ok((1, 0, None)));
+        assert_eq!(counter("DECR k 5"), Ok((-5, 0, None)));
+        assert_eq!(
+            counter("INCR k 2 TTL 60 INIT 10"),
+            Ok((2, 10, Some(Duration::from_secs(60))))
+        );
+        assert_eq!(counter("DECR k INIT 3"), Ok((-1, 3, None)));
+        assert_eq!(counter("INCR k INIT 1 INIT 2"), Err(()));
+        assert_eq!(counter("INCR k TTL"), Err(()));
+        assert_eq!(counter(&format!("DECR k {}", i64::MIN)), Err(()));
+    }
+
+    #[test]
+    fn multi_key_commands() {
+        match parse("MSET a 1 b 2 30") {
+            Ok(ClientMessage::SetMany { pairs, dur }) => {
+                assert_eq!(
+                    pairs,
+                    [
+                        ("a".to_string(), b"1".to_vec()),
+                        ("b".to_string(), b"2".to_vec())
+                    ]
+                );
+                assert_eq!(dur, Duration::from_secs(30));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(
+            parse("MSET a 1"),
+            Ok(ClientMessage::SetMany {
+                dur: DEFAULT_TTL,
+                ..
+            })
+        ));
+        assert!(parse("MSET 30").is_err());
+        assert!(parse("MGET").is_err());
+        assert!(parse("DEL").is_err());
+    }
+
+    #[tokio::test]
+    async fn node_messages() {
+        assert!(matches!(
+            parse("JOIN [::1]:7000"),
+            Ok(ClientMessage::JoinNode { addr }) if addr == "[::1]:7000".parse().unwrap()
+        ));
+        assert!(parse("JOIN somehost:7000").is_err());
+
+        // keys from RESP clients may hold anything, including what looks like another command
+        let keys = ["k", "two words", "x\nSYNCDEL victim\nSYNC y", "ключ"];
+        let mut input = Vec::new();
+        for key in keys {
+            let ttl = Some(Duration::from_millis(1500));
+            input.extend(ClientMessage::encode_replication(key, b"v\nw", ttl, 3));
+            input.extend(ClientMessage::encode_replicate_remove(key));
+        }
+        let messages = link_messages(&input).await;
+        assert_eq!(messages.len(), keys.len() * 2);
+        for (pair, expected) in messages.chunks(2).zip(keys) {
+            match &pair[0] {
+                ClientMessage::Replicate {
+                    key,
+                    ttl,
+                    flags: 3,
+                    value,
+                } => {
+                    assert_eq!(key, expected);
+                    assert_eq!(*ttl, Some(Duration::from_millis(1500)));
+                    assert_eq!(value, b"v\nw");
+                }
+                other => panic!("{:?}", other),
+            }
+            assert!(matches!(&pair[1], ClientMessage::ReplicateRemove { key } if key == expected));
+        }
+        assert!(matches!(
+            link_messages(&ClientMessage::encode_replicate_flush()).await[..],
+            [ClientMessage::ReplicateFlush]
+        ));
+
+        assert!(matches!(
+            ClientMessage::from_frame(&frame("SYNC 1 -1 0 1", b"kv")),
+            Ok(ClientMessage::Replicate { ttl: None, .. })
+        ));
+        // a key longer than the payload
+        assert!(ClientMessage::from_frame(&frame("SYNC 3 -1 0 0", b"kv")).is_err());
+        assert!(ClientMessage::payload_len(&format!("SYNC {} -1 0 1", usize::MAX)).is_none());
     }
 }
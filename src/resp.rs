@@ -0,0 +1,501 @@
+use std::time::Duration;
+
+use crate::{
+    database::{until_unix, Database, StoreMode, StoreResult, MAX_TTL},
+    frame::MAX_LINE_LEN,
+    message::{Outcome, Replication},
+};
+
+/// A redis command, for the subset of redis the database can answer.
+#[derive(Debug)]
+pub enum RespCommand {
+    // PING [MESSAGE]
+    Ping {
+        message: Option<Vec<u8>>,
+    },
+    // HELLO [PROTOVER ...]
+    Hello {
+        protover: Option<u8>,
+    },
+    // COMMAND [...], redis-cli asks for the command docs on startup
+    Command,
+    // GET KEY
+    Get {
+        key: String,
+    },
+    // SET KEY VALUE [NX|XX] [EX SECONDS|PX MILLISECONDS|EXAT UNIX_SECONDS|PXAT UNIX_MILLISECONDS]
+    Set {
+        key: String,
+        value: Vec<u8>,
+        mode: StoreMode,
+        ttl: Option<Duration>,
+    },
+    // DEL KEY [KEY...]
+    Del {
+        keys: Vec<String>,
+    },
+    // EXISTS KEY [KEY...]
+    Exists {
+        keys: Vec<String>,
+    },
+    // EXPIRE KEY SECONDS, PEXPIRE KEY MILLISECONDS
+    // EXPIREAT KEY UNIX_SECONDS, PEXPIREAT KEY UNIX_MILLISECONDS
+    // `None` is a time that already passed
+    Expire {
+        key: String,
+        ttl: Option<Duration>,
+    },
+    // TTL KEY, PTTL KEY
+    Ttl {
+        key: String,
+        millis: bool,
+    },
+}
+
+/// A reply value, encoded for RESP2 or RESP3 depending on what the connection negotiated.
+#[derive(Debug)]
+pub enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<RespValue>),
+    /// Sent as a flat array to RESP2 clients
+    Map(Vec<(RespValue, RespValue)>),
+}
+
+impl RespValue {
+    pub fn encode(&self, resp3: bool) -> Vec<u8> {
+        match self {
+            RespValue::Simple(s) => format!("+{}\r\n", s).into_bytes(),
+            RespValue::Error(s) => format!("-{}\r\n", s).into_bytes(),
+            RespValue::Integer(n) => format!(":{}\r\n", n).into_bytes(),
+            RespValue::Bulk(s) => {
+                let mut out = format!("${}\r\n", s.len()).into_bytes();
+                out.extend_from_slice(s);
+                out.extend_from_slice(b"\r\n");
+                out
+            }
+            RespValue::Null if resp3 => b"_\r\n".to_vec(),
+            RespValue::Null => b"$-1\r\n".to_vec(),
+            RespValue::Array(items) => {
+                let mut out = format!("*{}\r\n", items.len()).into_bytes();
+                for item in items {
+                    out.extend(item.encode(resp3));
+                }
+                out
+            }
+            RespValue::Map(pairs) => {
+                let mut out = match resp3 {
+                    true => format!("%{}\r\n", pairs.len()).into_bytes(),
+                    false => format!("*{}\r\n", pairs.len() * 2).into_bytes(),
+                };
+                for (k, v) in pairs {
+                    out.extend(k.encode(resp3));
+                    out.extend(v.encode(resp3));
+                }
+                out
+            }
+        }
+    }
+}
+
+fn syntax_error() -> String {
+    "ERR syntax error".to_string()
+}
+
+fn wrong_args(cmd: &str) -> String {
+    format!("ERR wrong number of arguments for '{}' command", cmd)
+}
+
+fn not_an_integer() -> String {
+    "ERR value is not an integer or out of range".to_string()
+}
+
+/// Keys are held to the length of a native request line, so every key can be replicated.
+fn key(arg: &[u8]) -> Result<String, String> {
+    if arg.len() > MAX_LINE_LEN {
+        return Err("ERR key too long".to_string());
+    }
+    String::from_utf8(arg.to_vec()).map_err(|_| "ERR keys must be UTF-8".to_string())
+}
+
+fn keys(args: &[Vec<u8>]) -> Result<Vec<String>, String> {
+    args.iter().map(|k| key(k)).collect()
+}
+
+fn int<T: std::str::FromStr>(arg: &[u8]) -> Result<T, String> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(not_an_integer)
+}
+
+impl RespCommand {
+    /// Parses the arguments of a RESP request, the error is the message for the `-ERR` reply.
+    /// Values are taken as they are, the command name and keys have to be UTF-8.
+    pub fn parse(args: Vec<Vec<u8>>) -> Result<Self, String> {
+        let mut args = args.into_iter();
+        let name = args.next().ok_or_else(|| "ERR empty command".to_string())?;
+        let name = String::from_utf8_lossy(&name).into_owned();
+        let cmd = name.to_ascii_lowercase();
+        let args = args.collect::<Vec<_>>();
+
+        let arity = |min: usize, max: usize| {
+            (min..=max)
+                .contains(&args.len())
+                .then_some(())
+                .ok_or_else(|| wrong_args(&cmd))
+        };
+
+        match cmd.as_str() {
+            "ping" => {
+                arity(0, 1)?;
+                Ok(RespCommand::Ping {
+                    message: args.into_iter().next(),
+                })
+            }
+            "hello" => {
+                let protover = match args.first() {
+                    Some(v) => Some(int(v)?),
+                    None => None,
+                };
+                Ok(RespCommand::Hello { protover })
+            }
+            "command" => Ok(RespCommand::Command),
+            "get" => {
+                arity(1, 1)?;
+                Ok(RespCommand::Get {
+                    key: key(&args[0])?,
+                })
+            }
+            "set" => {
+                if args.len() < 2 {
+                    return Err(wrong_args(&cmd));
+                }
+                let mut args = args.into_iter();
+                let key = key(&args.next().expect("checked above"))?;
+                let value = args.next().expect("checked above");
+
+                let mut mode = StoreMode::Set;
+                let mut ttl = None;
+                while let Some(opt) = args.next() {
+                    match opt.to_ascii_uppercase().as_slice() {
+                        b"NX" if mode == StoreMode::Set => mode = StoreMode::Add,
+                        b"XX" if mode == StoreMode::Set => mode = StoreMode::Replace,
+                        unit @ (b"EX" | b"PX" | b"EXAT" | b"PXAT") if ttl.is_none() => {
+                            let invalid = || "ERR invalid expire time in 'set' command".to_string();
+                            let n = int::<u64>(&args.next().ok_or_else(syntax_error)?)?;
+                            if n == 0 {
+                                return Err(invalid());
+                            }
+                            let dur = match unit {
+                                b"EX" => Duration::from_secs(n),
+                                b"PX" => Duration::from_millis(n),
+                                b"EXAT" => {
+                                    until_unix(Duration::from_secs(n)).ok_or_else(invalid)?
+                                }
+                                _ => until_unix(Duration::from_millis(n)).ok_or_else(invalid)?,
+                            };
+                            if dur > MAX_TTL {
+                                return Err(invalid());
+                            }
+                            ttl = Some(dur);
+                        }
+                        _ => return Err(syntax_error()),
+                    }
+                }
+
+                Ok(RespCommand::Set {
+                    key,
+                    value,
+                    mode,
+                    ttl,
+                })
+            }
+            "del" => {
+                arity(1, usize::MAX)?;
+                Ok(RespCommand::Del { keys: keys(&args)? })
+            }
+            "exists" => {
+                arity(1, usize::MAX)?;
+                Ok(RespCommand::Exists { keys: keys(&args)? })
+            }
+            "expire" | "pexpire" | "expireat" | "pexpireat" => {
+                arity(2, 2)?;
+                let n = int::<i64>(&args[1])?;
+                let n = u64::try_from(n).ok().filter(|n| *n > 0);
+                let ttl = match cmd.as_str() {
+                    "expire" => n.map(|n| Some(Duration::from_secs(n))),
+                    "pexpire" => n.map(|n| Some(Duration::from_millis(n))),
+                    "expireat" => n.map(|n| until_unix(Duration::from_secs(n))),
+                    _ => n.map(|n| until_unix(Duration::from_millis(n))),
+                };
+                // a time the clock can't represent is as invalid as one past MAX_TTL
+                let ttl = ttl
+                    .map(|ttl| {
+                        ttl.filter(|ttl| *ttl <= MAX_TTL)
+                            .ok_or_else(|| format!("ERR invalid expire time in '{}' command", cmd))
+                    })
+                    .transpose()?;
+                Ok(RespCommand::Expire {
+                    key: key(&args[0])?,
+                    ttl: ttl.filter(|ttl| !ttl.is_zero()),
+                })
+            }
+            "ttl" | "pttl" => {
+                arity(1, 1)?;
+                Ok(RespCommand::Ttl {
+                    key: key(&args[0])?,
+                    millis: cmd == "pttl",
+                })
+            }
+            _ => Err(format!("ERR unknown command '{}'", name)),
+        }
+    }
+
+    /// Runs everything but `HELLO`, which changes the connection rather than the database and is
+    /// handled by the server.
+    pub async fn execute(self, db: &Database, resp3: bool) -> Outcome {
+        let (reply, replicate) = self.run(db).await;
+        Outcome {
+            reply: reply.encode(resp3),
+            replicate,
+        }
+    }
+
+    async fn run(self, db: &Database) -> (RespValue, Vec<Replication>) {
+        match self {
+            RespCommand::Ping { message: None } => (RespValue::Simple("PONG".to_string()), vec![]),
+            RespCommand::Ping { message: Some(m) } => (RespValue::Bulk(m), vec![]),
+            RespCommand::Hello { .. } | RespCommand::Command => (RespValue::Array(vec![]), vec![]),
+            RespCommand::Get { key } => {
+                let value = db.get_or_remove(key).await.and_then(|d| d.inner());
+                (value.map_or(RespValue::Null, RespValue::Bulk), vec![])
+            }
+            RespCommand::Set {
+                key,
+                value,
+                mode,
+                ttl,
+            } => match db.store(key.clone(), value, 0, ttl, mode).await {
+                StoreResult::Stored => (
+                    RespValue::Simple("OK".to_string()),
+                    vec![Replication::Set(key)],
+                ),
+                StoreResult::OutOfMemory => (
+                    RespValue::Error(
+                        "OOM command not allowed when used memory > 'maxmemory'.".to_string(),
+                    ),
+                    vec![],
+                ),
+                _ => (RespValue::Null, vec![]),
+            },
+            RespCommand::Del { keys } => {
+                let mut n = 0;
+                for key in &keys {
+                    n += db.remove(key).await as i64;
+                }
+                let replicate = keys.into_iter().map(Replication::Remove).collect();
+                (RespValue::Integer(n), replicate)
+            }
+            RespCommand::Exists { keys } => {
+                let mut n = 0;
+                for key in keys {
+                    let data = db.get_or_remove(key).await;
+                    n += data.is_some_and(|d| d.inner().is_some()) as i64;
+                }
+                (RespValue::Integer(n), vec![])
+            }
+            RespCommand::Expire { key, ttl: None } => {
+                let removed = db.remove(&key).await;
+                (
+                    RespValue::Integer(removed as i64),
+                    vec![Replication::Remove(key)],
+                )
+            }
+            RespCommand::Expire { key, ttl } => match db.touch(&key, ttl).await {
+                true => (RespValue::Integer(1), vec![Replication::Set(key)]),
+                false => (RespValue::Integer(0), vec![]),
+            },
+            RespCommand::Ttl { key, millis } => {
+                let data = db.get_or_remove(key).await.filter(|d| d.inner().is_some());
+                let ttl = match data {
+                    None => -2,
+                    Some(d) => d.remaining_ttl().map_or(-1, |ttl| match millis {
+                        true => ttl.as_millis() as i64,
+                        // rounded like redis does, a fresh `EX 100` reads back as 100
+                        false => ((ttl.as_millis() + 500) / 1000) as i64,
+                    }),
+                };
+                (RespValue::Integer(ttl), vec![])
+            }
+        }
+    }
+}
+
+/// The `HELLO` reply describing the server.
+pub fn hello_reply(protover: u8) -> RespValue {
+    let bulk = |s: &str| RespValue::Bulk(s.as_bytes().to_vec());
+    let field = |k: &str, v: RespValue| (bulk(k), v);
+    RespValue::Map(vec![
+        field("server", bulk("rscache")),
+        field("version", bulk(env!("CARGO_PKG_VERSION"))),
+        field("proto", RespValue::Integer(protover.into())),
+        field("mode", bulk("standalone")),
+        field("role", bulk("master")),
+        field("modules", RespValue::Array(vec![])),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<RespCommand, String> {
+        RespCommand::parse(args.iter().map(|a| a.as_bytes().to_vec()).collect())
+    }
+
+    fn set(args: &[&str]) -> Result<(StoreMode, Option<Duration>), String> {
+        match parse(args)? {
+            RespCommand::Set { mode, ttl, .. } => Ok((mode, ttl)),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    fn expire(args: &[&str]) -> Result<Option<Duration>, String> {
+        match parse(args)? {
+            RespCommand::Expire { ttl, .. } => Ok(ttl),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn command_names_are_case_insensitive() {
+        assert!(matches!(parse(&["get", "k"]), Ok(RespCommand::Get { key }) if key == "k"));
+        assert!(matches!(parse(&["GeT", "k"]), Ok(RespCommand::Get { .. })));
+        assert_eq!(parse(&["NOPE"]).unwrap_err(), "ERR unknown command 'NOPE'");
+        assert_eq!(parse(&[]).unwrap_err(), "ERR empty command");
+    }
+
+    #[test]
+    fn arity() {
+        assert_eq!(
+            parse(&["get"]).unwrap_err(),
+            "ERR wrong number of arguments for 'get' command"
+        );
+        assert!(parse(&["get", "a", "b"]).is_err());
+        assert!(parse(&["set", "k"]).is_err());
+        assert!(parse(&["ping", "a", "b"]).is_err());
+        assert!(parse(&["del"]).is_err());
+        assert!(matches!(
+            parse(&["del", "a", "b"]),
+            Ok(RespCommand::Del { keys }) if keys == ["a", "b"]
+        ));
+    }
+
+    #[test]
+    fn set_options() {
+        let secs = Duration::from_secs;
+        assert_eq!(set(&["set", "k", "v"]), Ok((StoreMode::Set, None)));
+        assert_eq!(set(&["set", "k", "v", "nx"]), Ok((StoreMode::Add, None)));
+        assert_eq!(
+            set(&["set", "k", "v", "XX", "EX", "10"]),
+            Ok((StoreMode::Replace, Some(secs(10))))
+        );
+        assert_eq!(
+            set(&["set", "k", "v", "PX", "1500", "NX"]),
+            Ok((StoreMode::Add, Some(Duration::from_millis(1500))))
+        );
+        assert_eq!(
+            set(&["set", "k", "v", "EXAT", "1"]),
+            Ok((StoreMode::Set, Some(Duration::ZERO)))
+        );
+
+        let syntax = Err(syntax_error());
+        assert_eq!(set(&["set", "k", "v", "NX", "XX"]), syntax);
+        assert_eq!(set(&["set", "k", "v", "EX", "1", "PX", "1"]), syntax);
+        assert_eq!(set(&["set", "k", "v", "EX"]), syntax);
+        assert_eq!(set(&["set", "k", "v", "KEEPTTL"]), syntax);
+        assert_eq!(set(&["set", "k", "v", "EX", "x"]), Err(not_an_integer()));
+    }
+
+    #[test]
+    fn set_expire_times() {
+        let invalid = Err("ERR invalid expire time in 'set' command".to_string());
+        assert_eq!(set(&["set", "k", "v", "EX", "0"]), invalid);
+        let max = MAX_TTL.as_secs();
+        assert!(set(&["set", "k", "v", "EX", &max.to_string()]).is_ok());
+        assert_eq!(
+            set(&["set", "k", "v", "EX", &(max + 1).to_string()]),
+            invalid
+        );
+        for unit in ["EX", "PX", "EXAT", "PXAT"] {
+            assert_eq!(
+                set(&["set", "k", "v", unit, &u64::MAX.to_string()]),
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn expire_times() {
+        assert_eq!(
+            expire(&["expire", "k", "10"]),
+            Ok(Some(Duration::from_secs(10)))
+        );
+        assert_eq!(
+            expire(&["pexpire", "k", "10"]),
+            Ok(Some(Duration::from_millis(10)))
+        );
+        // zero, negative and past times expire the key right away
+        assert_eq!(expire(&["expire", "k", "0"]), Ok(None));
+        assert_eq!(expire(&["expire", "k", "-5"]), Ok(None));
+        assert_eq!(expire(&["expireat", "k", "1"]), Ok(None));
+        assert!(expire(&["pexpireat", "k", "4000000000000"])
+            .unwrap()
+            .is_some());
+
+        assert_eq!(expire(&["expire", "k", "x"]), Err(not_an_integer()));
+        for cmd in ["expire", "pexpire", "expireat", "pexpireat"] {
+            assert_eq!(
+                expire(&[cmd, "k", &i64::MAX.to_string()]),
+                Err(format!("ERR invalid expire time in '{}' command", cmd))
+            );
+        }
+    }
+
+    #[test]
+    fn keys_must_be_utf8() {
+        let args = vec![b"GET".to_vec(), vec![0xff]];
+        assert_eq!(
+            RespCommand::parse(args).unwrap_err(),
+            "ERR keys must be UTF-8"
+        );
+        let args = vec![b"SET".to_vec(), b"k".to_vec(), vec![0xff]];
+        assert!(RespCommand::parse(args).is_ok());
+
+        let long = "k".repeat(MAX_LINE_LEN + 1);
+        assert_eq!(parse(&["get", &long]).unwrap_err(), "ERR key too long");
+        assert!(parse(&["get", &long[1..]]).is_ok());
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(RespValue::Null.encode(false), b"$-1\r\n");
+        assert_eq!(RespValue::Null.encode(true), b"_\r\n");
+        assert_eq!(
+            RespValue::Bulk(b"a\r\nb".to_vec()).encode(false),
+            b"$4\r\na\r\nb\r\n"
+        );
+        let map = RespValue::Map(vec![(
+            RespValue::Simple("k".into()),
+            RespValue::Integer(-1),
+        )]);
+        assert_eq!(map.encode(false), b"*2\r\n+k\r\n:-1\r\n");
+        assert_eq!(map.encode(true), b"%1\r\n+k\r\n:-1\r\n");
+        let array = RespValue::Array(vec![RespValue::Error("ERR x".into()), RespValue::Null]);
+        assert_eq!(array.encode(false), b"*2\r\n-ERR x\r\n$-1\r\n");
+    }
+}
@@ -1,25 +1,54 @@
 use crate::{
-    client::{Client, ClientState},
-    config::Config,
-    database::Database,
-    message::{self, ClientMessage},
+    aof::AppendLog,
+    client::{Client, ClientState, PeerAddr},
+    config::{Changes, Config, ConfigError, Protocol},
+    database::{CounterError, Database, StoreMode, StoreResult},
+    frame::{Frame, Request},
+    memcache::MemcacheCommand,
+    message::{self, ClientMessage, Outcome, Replication, DEFAULT_TTL},
+    resp::{self, RespCommand, RespValue},
+    snapshot,
 };
 use core::fmt;
-use std::{collections::HashMap, net::SocketAddr, process::exit, rc::Rc, sync::Arc};
-use tokio::sync::{
-    mpsc::{Receiver, Sender},
-    RwLock,
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::exit,
+    sync::Arc,
+    time::Duration,
 };
+use tokio::sync::mpsc::{Receiver, Sender};
 use tracing::debug_span;
+use tracing_subscriber::{filter::LevelFilter, reload, Registry};
+
+/// Changes the level of the log subscriber installed in `main`.
+pub type LogLevelHandle = reload::Handle<LevelFilter, Registry>;
 
 #[derive(Debug)]
 pub struct Server {
-    client: HashMap<SocketAddr, Client>,
+    client: HashMap<PeerAddr, Client>,
     rx: Receiver<ServerMessages>,
     db: Database,
     config: Arc<Config>,
-    nodes: Vec<Client>,
+    nodes: Vec<ChildNode>,
     parent: Option<Client>,
+    /// Index into `nodes` of the child the next `JOIN` gets deferred to once we are full.
+    next_deferral: usize,
+    /// Set once the log has been replayed in `start_daemon`
+    aof: Option<AppendLog>,
+    /// For `log_level` to take effect, with the level to go back to when it's unset
+    log_level: Option<(LogLevelHandle, LevelFilter)>,
+    /// For tasks that report back to the server, set in `start_daemon`
+    tx: Option<Sender<ServerMessages>>,
+}
+
+/// A node that joined us with `JOIN <addr>`. `listen_addr` is the address the node accepts
+/// connections on, which is what we hand out in `DEFERED` replies.
+#[derive(Debug)]
+pub struct ChildNode {
+    listen_addr: SocketAddr,
+    client: Client,
 }
 
 impl Drop for Server {
@@ -51,20 +80,13 @@ impl fmt::Display for Server {
 
 #[derive(Debug)]
 pub enum ServerMessages {
-    NewMessage(String, SocketAddr),
-    NewClient(SocketAddr, crate::client::Client, Sender<ServerMessages>),
-    RemoveClient(SocketAddr),
-}
-
-#[derive(Debug)]
-struct Error {
-    message: String,
-}
-
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.message)
-    }
+    NewMessage(Request, PeerAddr),
+    NewClient(PeerAddr, crate::client::Client, Sender<ServerMessages>),
+    RemoveClient(PeerAddr),
+    /// Reread the configuration, on SIGHUP
+    ReloadConfig,
+    /// Drop every entry, once the delay of a `flush_all` is up
+    Flush,
 }
 
 impl Server {
@@ -81,33 +103,133 @@ impl Server {
             config,
             nodes: Vec::new(),
             parent,
+            next_deferral: 0,
+            aof: None,
+            log_level: None,
+            tx: None,
         }
     }
 
-    pub async fn start_daemon(mut self) {
+    /// Lets the config set the log level, `default` applies while it doesn't.
+    pub fn with_log_level(mut self, handle: LogLevelHandle, default: LevelFilter) -> Self {
+        self.log_level = Some((handle, default));
+        self.apply_log_level();
+        self
+    }
+
+    fn apply_log_level(&self) {
+        let Some((ref handle, default)) = self.log_level else {
+            return;
+        };
+        let level = self
+            .config
+            .log_level()
+            .map_or(default, |l| LevelFilter::from_level(l.into()));
+        if let Err(err) = handle.reload(level) {
+            tracing::error!(message = "Could not change the log level", %err);
+        }
+    }
+
+    pub async fn start_daemon(mut self, tx: Sender<ServerMessages>) {
         tracing::debug!(message = "Starting Server", %self);
         self.db.keep_valid().await;
+        if let Some(path) = self.config.snapshot_path() {
+            // an existing log holds everything since its last compaction, the snapshot can only
+            // be older
+            if !self.config.aof_path().is_some_and(Path::exists) {
+                match snapshot::load(&self.db, path).await {
+                    Ok(n) => tracing::info!(message = "Loaded snapshot", path = %path.display(), n),
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(err) => {
+                        tracing::error!(message = "Could not load snapshot", path = %path.display(), %err)
+                    }
+                }
+            }
+            if let Some(every) = self.config.snapshot_interval() {
+                self.save_periodically(path.to_path_buf(), every);
+            }
+        }
+        if let Some(path) = self.config.aof_path() {
+            match AppendLog::open(path, self.config.aof_fsync(), &self.db).await {
+                Ok((aof, n)) => {
+                    tracing::info!(message = "Replayed append-only log", path = %path.display(), n);
+                    if n == 0 {
+                        // a new log starts out with whatever the snapshot brought back
+                        if let Err(err) = aof.compact(&self.db).await {
+                            tracing::error!(message = "Could not compact append-only log", %err);
+                        }
+                    }
+                    aof.compact_periodically(self.db.clone());
+                    self.aof = Some(aof);
+                }
+                Err(err) => {
+                    tracing::error!(message = "Could not open append-only log", path = %path.display(), %err);
+                    exit(1);
+                }
+            }
+        }
+        reload_on_hangup(tx.clone());
+        self.tx = Some(tx.clone());
+        if let Some(ref mut parent) = self.parent {
+            // the parent pushes its writes down to us over the connection we joined with
+            parent.keep_open(tx).await;
+        }
         tokio::task::spawn(async move { self.listen_for_messages().await });
     }
 
+    fn save_periodically(&self, path: PathBuf, every: Duration) {
+        let db = self.db.clone();
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + every, every);
+            loop {
+                interval.tick().await;
+                if let Err(err) = snapshot::save(&db, &path).await {
+                    tracing::error!(message = "Could not save snapshot", path = %path.display(), %err);
+                }
+            }
+        });
+    }
+
     // TODO: deperecated
     async fn listen_for_messages(&mut self) {
         'main: while let Some(r) = self.rx.recv().await {
             match r {
-                ServerMessages::NewMessage(msg, addr) => {
+                ServerMessages::NewMessage(request, addr) => {
+                    let msg = match request {
+                        Request::Text(frame) => frame,
+                        Request::Resp(args) => {
+                            self.handle_resp(args, addr).await;
+                            continue 'main;
+                        }
+                    };
+                    if self.is_link(addr) {
+                        self.handle_link_message(msg, addr).await;
+                        continue 'main;
+                    }
+
                     let cl = self.client.get_mut(&addr);
                     if cl.is_none() {
                         continue 'main;
                     }
+                    if cl
+                        .as_ref()
+                        .is_some_and(|cl| cl.protocol() == Protocol::Memcached)
+                    {
+                        self.handle_memcache(msg, addr).await;
+                        continue 'main;
+                    }
 
                     let cl = cl.expect("Client should be in map");
 
-                    let clm = msg.parse::<ClientMessage>();
+                    let clm = ClientMessage::from_frame(&msg);
                     let msg = match clm {
                         Ok(msg) => msg,
                         Err(_) => {
-                            if let ClientState::SettingValue { key } = cl.get_state().await {
-                                ClientMessage::SetValue { key, value: msg }
+                            if let ClientState::SettingValue { key, .. } = cl.get_state().await {
+                                ClientMessage::SetValue {
+                                    key,
+                                    value: msg.line,
+                                }
                             } else {
                                 cl.send_messageln("Could not parse the messgae".to_string())
                                     .await;
@@ -117,95 +239,633 @@ impl Server {
                     };
 
                     match msg {
-                        message::ClientMessage::SetKey { key, dur } => {
-                            self.db.insert_key(key.to_string(), dur).await;
-                            cl.change_state_to_settingvalue(key).await;
+                        message::ClientMessage::SetKey { key, dur, mode } => {
+                            cl.change_state_to_settingvalue(key, dur, mode).await;
                         }
                         message::ClientMessage::SetValue { key, value } => {
-                            self.db.insert_key_value(key, value).await;
+                            // `key:value` lines are written with the defaults, unless they answer
+                            // a pending `SET`
+                            let (dur, mode) = match cl.get_state().await {
+                                ClientState::SettingValue { key: k, dur, mode } if k == key => {
+                                    (dur, mode)
+                                }
+                                _ => (DEFAULT_TTL, StoreMode::Set),
+                            };
+                            let res = self
+                                .db
+                                .insert_key_value(key.clone(), value, dur, mode)
+                                .await;
                             tracing::debug!("Set Value");
                             cl.change_state_to_settingkey().await;
+                            if res == StoreResult::Stored {
+                                self.replicate(&key, None).await;
+                            }
+                            let cl = self.client.get_mut(&addr).expect("Client should be in map");
+                            cl.send_messageln(store_reply(mode, res).to_string()).await;
+                        }
+                        message::ClientMessage::Set {
+                            key,
+                            dur,
+                            mode,
+                            value,
+                        } => {
+                            let res = self
+                                .db
+                                .insert_key_value(key.clone(), value, dur, mode)
+                                .await;
+                            cl.change_state_to_settingkey().await;
+                            if res == StoreResult::Stored {
+                                self.replicate(&key, None).await;
+                            }
+                            let cl = self.client.get_mut(&addr).expect("Client should be in map");
+                            cl.send_messageln(store_reply(mode, res).to_string()).await;
                         }
                         message::ClientMessage::GetValue { key } => {
                             let v = self.db.get_or_remove(key.to_string()).await;
                             let v = match v {
                                 Some(v) => v.to_owned().inner(),
                                 None => {
-                                    let v = format!("KEY={{{}}} does not exists\n", key);
-                                    Some(v)
+                                    let v = format!("KEY={{{}}} does not exists", key);
+                                    Some(v.into_bytes())
                                 }
                             };
 
                             match v {
-                                Some(v) => cl.send_message(v).await,
+                                Some(mut v) => {
+                                    v.push(b'\n');
+                                    cl.send_messageb(&v).await;
+                                }
                                 None => {
                                     let v = format!("KEY={{{key}}} is empty");
                                     cl.send_messageln(v).await;
                                 }
                             }
                         }
-                        message::ClientMessage::JoinNode { addr } => {
-                            match self.client.remove(&addr) {
-                                Some(client) => {
-                                    let res = self.add_node(client).await;
-                                    match res {
-                                        Ok(client) => {
-                                            client.send_messageb(b"OK").await;
-                                        }
-                                        Err(op_client) => {
-                                            if let Some(client) = op_client {
-                                                self.client.insert(addr, client);
-                                            } else {
-                                                unreachable!("This should not happen: There should always be a last client");
-                                            }
+                        message::ClientMessage::GetWithToken { key } => {
+                            let data = self.db.get_or_remove(key.clone()).await;
+                            match data.and_then(|d| Some((d.cas(), d.inner()?))) {
+                                Some((token, value)) => {
+                                    let mut reply =
+                                        format!("{} {}\n", token, value.len()).into_bytes();
+                                    reply.extend(value);
+                                    reply.push(b'\n');
+                                    cl.send_messageb(&reply).await;
+                                }
+                                None => {
+                                    let v = format!("KEY={{{}}} does not exists", key);
+                                    cl.send_messageln(v).await;
+                                }
+                            }
+                        }
+                        message::ClientMessage::GetMany { keys } => {
+                            let found = self.db.get_many(&keys).await;
+                            let mut reply = Vec::new();
+                            for (key, data) in keys.iter().zip(found) {
+                                match data.and_then(|d| d.inner()) {
+                                    Some(value) => {
+                                        let header = format!("VALUE {} {}\n", key, value.len());
+                                        reply.extend(header.into_bytes());
+                                        reply.extend(value);
+                                        reply.push(b'\n');
+                                    }
+                                    None => reply.extend(format!("MISS {}\n", key).into_bytes()),
+                                }
+                            }
+                            reply.extend_from_slice(b"END\n");
+                            cl.send_messageb(&reply).await;
+                        }
+                        message::ClientMessage::SetMany { pairs, dur } => {
+                            let keys = pairs.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>();
+                            if self.db.store_many(pairs, Some(dur)).await.is_err() {
+                                cl.send_messageln("ERROR out of memory".to_string()).await;
+                                continue 'main;
+                            }
+                            let n = keys.len();
+                            let changes = keys.into_iter().map(Replication::Set).collect();
+                            self.propagate(changes, None).await;
+                            let cl = self.client.get_mut(&addr).expect("Client should be in map");
+                            cl.send_messageln(format!("STORED {}", n)).await;
+                        }
+                        message::ClientMessage::Counter {
+                            key,
+                            delta,
+                            init,
+                            ttl,
+                        } => match self.db.counter(key.clone(), delta, init, ttl).await {
+                            Ok(n) => {
+                                self.replicate(&key, None).await;
+                                let cl =
+                                    self.client.get_mut(&addr).expect("Client should be in map");
+                                cl.send_messageln(n.to_string()).await;
+                            }
+                            Err(CounterError::NotANumber) => {
+                                cl.send_messageln("ERROR value is not a number".to_string())
+                                    .await;
+                            }
+                            Err(CounterError::Overflow) => {
+                                cl.send_messageln("ERROR counter would overflow".to_string())
+                                    .await;
+                            }
+                            Err(CounterError::OutOfMemory) => {
+                                cl.send_messageln("ERROR out of memory".to_string()).await;
+                            }
+                            Err(CounterError::NotFound) => {
+                                unreachable!("missing counters are created")
+                            }
+                        },
+                        msg @ (message::ClientMessage::Touch { .. }
+                        | message::ClientMessage::Expire { .. }
+                        | message::ClientMessage::Persist { .. }) => {
+                            let (key, found) = match msg {
+                                message::ClientMessage::Touch { key } => {
+                                    let found = self.db.refresh(&key).await;
+                                    (key, found)
+                                }
+                                message::ClientMessage::Expire { key, dur } => {
+                                    let found = self.db.touch(&key, Some(dur)).await;
+                                    (key, found)
+                                }
+                                message::ClientMessage::Persist { key } => {
+                                    let found = self.db.touch(&key, None).await;
+                                    (key, found)
+                                }
+                                _ => unreachable!(),
+                            };
+                            match found {
+                                true => {
+                                    self.replicate(&key, None).await;
+                                    let cl = self
+                                        .client
+                                        .get_mut(&addr)
+                                        .expect("Client should be in map");
+                                    cl.send_messageln("OK".to_string()).await;
+                                }
+                                false => cl.send_messageln("NOT_FOUND".to_string()).await,
+                            }
+                        }
+                        message::ClientMessage::Ttl { key, millis } => {
+                            let data = self
+                                .db
+                                .get_or_remove(key)
+                                .await
+                                .filter(|d| d.inner().is_some());
+                            let ttl = match data {
+                                None => -2,
+                                Some(d) => d.remaining_ttl().map_or(-1, |ttl| match millis {
+                                    true => ttl.as_millis() as i64,
+                                    false => ((ttl.as_millis() + 500) / 1000) as i64,
+                                }),
+                            };
+                            cl.send_messageln(ttl.to_string()).await;
+                        }
+                        message::ClientMessage::Save { background } => {
+                            let Some(path) = self.config.snapshot_path() else {
+                                cl.send_messageln("ERROR no snapshot_path configured".to_string())
+                                    .await;
+                                continue 'main;
+                            };
+                            let path = path.to_path_buf();
+                            if background {
+                                let db = self.db.clone();
+                                tokio::task::spawn(async move {
+                                    match snapshot::save(&db, &path).await {
+                                        Ok(n) => tracing::info!(message = "Saved snapshot", n),
+                                        Err(err) => {
+                                            tracing::error!(message = "Could not save snapshot", %err)
                                         }
                                     }
+                                });
+                                cl.send_messageln("OK saving in the background".to_string())
+                                    .await;
+                                continue 'main;
+                            }
+                            match snapshot::save(&self.db, &path).await {
+                                Ok(n) => cl.send_messageln(format!("SAVED {}", n)).await,
+                                Err(err) => cl.send_messageln(format!("ERROR {}", err)).await,
+                            }
+                        }
+                        message::ClientMessage::Stats => {
+                            let mut reply = String::new();
+                            for (name, value) in self.db.stats().await.fields() {
+                                reply.push_str(&format!("STAT {} {}\n", name, value));
+                            }
+                            reply.push_str("END");
+                            cl.send_messageln(reply).await;
+                        }
+                        message::ClientMessage::Reload => {
+                            let reply = match self.reload().await {
+                                Ok(changes) => {
+                                    let mut reply = String::new();
+                                    for field in changes.live {
+                                        reply.push_str(&format!("CHANGED {}\n", field));
+                                    }
+                                    for field in changes.restart {
+                                        reply.push_str(&format!("RESTART {}\n", field));
+                                    }
+                                    for field in changes.ignored {
+                                        reply.push_str(&format!("IGNORED {}\n", field));
+                                    }
+                                    reply.push_str("END");
+                                    reply
                                 }
-                                None => {
-                                    tracing::error!(message = "Client not found", %addr);
-                                    tracing::error!(message = "Clinet was able to send a message over TCP and pretened as a Client", %addr);
-                                    tracing::debug!(message = "FAILED TO JOIN AS NODE", %addr);
+                                Err(err) => {
+                                    let lines = err.to_string();
+                                    let lines = lines.lines().map(str::trim).collect::<Vec<_>>();
+                                    format!("ERROR {}", lines.join(" "))
                                 }
+                            };
+                            let cl = self.client.get_mut(&addr).expect("Client should be in map");
+                            cl.send_messageln(reply).await;
+                        }
+                        message::ClientMessage::Delete { keys } => {
+                            let mut n = 0;
+                            for key in &keys {
+                                n += self.db.remove(key).await as usize;
                             }
+                            let changes = keys.into_iter().map(Replication::Remove).collect();
+                            self.propagate(changes, None).await;
+                            let cl = self.client.get_mut(&addr).expect("Client should be in map");
+                            cl.send_messageln(format!("DELETED {}", n)).await;
+                        }
+                        message::ClientMessage::JoinNode { addr: listen_addr } => {
+                            self.join(addr, listen_addr).await;
                         }
                         message::ClientMessage::Defered { addr } => {
                             // TODO: Defer the connection to the next node in the list
                             tracing::debug!(message = "Defered", %addr);
                         }
+                        message::ClientMessage::Replicate { .. }
+                        | message::ClientMessage::ReplicateRemove { .. }
+                        | message::ClientMessage::ReplicateFlush => {
+                            tracing::warn!(message = "SYNC from a client that is not a node", %addr);
+                            cl.send_messageln("SYNC is only accepted from nodes".to_string())
+                                .await;
+                        }
                     }
                 }
-                ServerMessages::NewClient(addr, client, tx) => {
+                ServerMessages::NewClient(addr, mut client, tx) => {
                     tracing::debug!(message = "New client", %addr);
-                    let mut client = self.client.insert(addr, client);
-                    if let Some(ref mut client) = client {
-                        client.keep_open(tx).await;
+                    client.keep_open(tx).await;
+                    self.client.insert(addr, client);
+                }
+                ServerMessages::Flush => self.flush(None).await,
+                ServerMessages::ReloadConfig => {
+                    if let Err(err) = self.reload().await {
+                        tracing::error!(message = "Could not reload config", %err);
                     }
                 }
                 ServerMessages::RemoveClient(addr) => {
                     if let Some(client) = self.client.remove(&addr) {
                         client.disconnect().await;
                         tracing::debug!(message = "removed client at ", %addr);
+                    } else if self.drop_connection_to_nodes(addr).await {
+                        tracing::info!(message = "Node left", %addr);
+                    } else if self.parent.as_ref().is_some_and(|p| p.addr() == addr) {
+                        tracing::warn!(message = "Lost connection to parent", %addr);
+                        self.parent = None;
                     }
                 }
             }
         }
     }
 
+    /// Rereads the configuration and applies the settings that can change while running, see
+    /// [`Config::reload`]. Returns the settings that changed.
+    async fn reload(&mut self) -> Result<Changes, ConfigError> {
+        let (config, changes) = self.config.reload()?;
+        let config = Arc::new(config);
+        self.db.reconfigure(Arc::clone(&config)).await;
+        self.config = config;
+        self.apply_log_level();
+
+        tracing::info!(message = "Reloaded config", changed = ?changes.live);
+        if !changes.restart.is_empty() {
+            tracing::warn!(
+                message = "Changed settings only apply after a restart",
+                restart = ?changes.restart
+            );
+        }
+        Ok(changes)
+    }
+
+    /// Registers the client at `addr` as a child node reachable on `listen_addr`.
+    async fn join(&mut self, addr: PeerAddr, listen_addr: SocketAddr) {
+        // with TLS, only a node holding a certificate from our CA may join
+        if self.config.tls() {
+            if let Some(client) = self.client.get_mut(&addr).filter(|c| !c.verified()) {
+                tracing::warn!(message = "Refusing JOIN without a client certificate", %addr);
+                client
+                    .send_messageln("ERROR JOIN needs a client certificate".to_string())
+                    .await;
+                return;
+            }
+        }
+        match self.client.remove(&addr) {
+            Some(client) => match self.add_node(listen_addr, client).await {
+                Ok(client) => {
+                    client.send_messageb(b"OK\n").await;
+                    self.sync_node(addr).await;
+                }
+                Err(client) => {
+                    self.client.insert(addr, client);
+                }
+            },
+            None => {
+                tracing::error!(message = "Client not found", %addr);
+                tracing::error!(message = "Clinet was able to send a message over TCP and pretened as a Client", %addr);
+                tracing::debug!(message = "FAILED TO JOIN AS NODE", %addr);
+            }
+        }
+    }
+
+    async fn handle_memcache(&mut self, frame: Frame, addr: PeerAddr) {
+        let outcome = match MemcacheCommand::from_frame(&frame) {
+            Ok(cmd) => cmd.execute(&self.db).await,
+            Err(err) => {
+                // the JOIN handshake is the same whatever the client port speaks
+                if let Ok(ClientMessage::JoinNode { addr: listen_addr }) =
+                    frame.text().ok_or(()).and_then(str::parse)
+                {
+                    self.join(addr, listen_addr).await;
+                    return;
+                }
+                Outcome {
+                    reply: err.reply(),
+                    replicate: vec![],
+                }
+            }
+        };
+
+        self.propagate(outcome.replicate, None).await;
+        if let Some(cl) = self.client.get_mut(&addr) {
+            if !outcome.reply.is_empty() {
+                cl.send_messageb(&outcome.reply).await;
+            }
+        }
+    }
+
+    async fn handle_resp(&mut self, args: Vec<Vec<u8>>, addr: PeerAddr) {
+        let Some(cl) = self.client.get_mut(&addr) else {
+            return;
+        };
+
+        let outcome = match RespCommand::parse(args) {
+            Ok(RespCommand::Hello { protover }) => {
+                let reply = match protover {
+                    Some(v @ 2..=3) => {
+                        cl.set_resp3(v == 3);
+                        resp::hello_reply(v)
+                    }
+                    Some(_) => RespValue::Error("NOPROTO unsupported protocol version".to_string()),
+                    None => resp::hello_reply(if cl.resp3() { 3 } else { 2 }),
+                };
+                Outcome {
+                    reply: reply.encode(cl.resp3()),
+                    replicate: vec![],
+                }
+            }
+            Ok(cmd) => {
+                let resp3 = cl.resp3();
+                cmd.execute(&self.db, resp3).await
+            }
+            Err(err) => Outcome {
+                reply: RespValue::Error(err).encode(cl.resp3()),
+                replicate: vec![],
+            },
+        };
+
+        self.propagate(outcome.replicate, None).await;
+        if let Some(cl) = self.client.get_mut(&addr) {
+            cl.send_messageb(&outcome.reply).await;
+        }
+    }
+
+    /// Node links are the connections to our children and to our parent, everything else is a
+    /// client.
+    fn is_link(&self, addr: PeerAddr) -> bool {
+        self.nodes.iter().any(|n| n.client.addr() == addr)
+            || self.parent.as_ref().is_some_and(|p| p.addr() == addr)
+    }
+
+    fn links_mut(&mut self) -> impl Iterator<Item = &mut Client> {
+        self.nodes
+            .iter_mut()
+            .map(|n| &mut n.client)
+            .chain(self.parent.iter_mut())
+    }
+
+    async fn handle_link_message(&mut self, frame: Frame, addr: PeerAddr) {
+        match ClientMessage::from_frame(&frame) {
+            Ok(ClientMessage::Replicate {
+                key,
+                ttl,
+                flags,
+                value,
+            }) => {
+                tracing::debug!(message = "Replicating", %key, from = %addr);
+                self.db.insert_replica(key.clone(), value, flags, ttl).await;
+                self.replicate(&key, Some(addr)).await;
+            }
+            Ok(ClientMessage::ReplicateRemove { key }) => {
+                tracing::debug!(message = "Replicating removal", %key, from = %addr);
+                self.db.remove(&key).await;
+                self.replicate_remove(&key, Some(addr)).await;
+            }
+            Ok(ClientMessage::ReplicateFlush) => {
+                tracing::debug!(message = "Replicating flush", from = %addr);
+                self.flush(Some(addr)).await;
+            }
+            _ => {
+                tracing::warn!(message = "Unexpected message from node", %addr, line = %String::from_utf8_lossy(&frame.line));
+            }
+        }
+    }
+
+    /// Sends the value we hold for `key` to every node link except `from`, the one the write
+    /// came in on. The topology is a tree, so flooding like this reaches every node exactly once.
+    /// Every write passes through here or `replicate_remove`, so this is also where it's logged,
+    /// which has to happen before the client is answered.
+    async fn replicate(&mut self, key: &str, from: Option<PeerAddr>) {
+        let Some(data) = self.db.get_or_remove(key.to_string()).await else {
+            return;
+        };
+        let Some(value) = data.inner() else {
+            return;
+        };
+
+        if let Some(ref aof) = self.aof {
+            let res = aof
+                .append_set(key, &value, data.remaining_ttl(), data.flags())
+                .await;
+            if let Err(err) = res {
+                tracing::error!(message = "Could not write to append-only log", %key, %err);
+            }
+        }
+
+        let msg =
+            ClientMessage::encode_replication(key, &value, data.remaining_ttl(), data.flags());
+        for link in self.links_mut().filter(|l| Some(l.addr()) != from) {
+            link.send_messageb(&msg).await;
+        }
+    }
+
+    /// Tells every node link except `from` that `key` is gone.
+    async fn replicate_remove(&mut self, key: &str, from: Option<PeerAddr>) {
+        if let Some(ref aof) = self.aof {
+            if let Err(err) = aof.append_del(key).await {
+                tracing::error!(message = "Could not write to append-only log", %key, %err);
+            }
+        }
+
+        let msg = ClientMessage::encode_replicate_remove(key);
+        for link in self.links_mut().filter(|l| Some(l.addr()) != from) {
+            link.send_messageb(&msg).await;
+        }
+    }
+
+    async fn propagate(&mut self, changes: Vec<Replication>, from: Option<PeerAddr>) {
+        for change in changes {
+            match change {
+                Replication::Set(key) => self.replicate(&key, from).await,
+                Replication::Remove(key) => self.replicate_remove(&key, from).await,
+                Replication::Flush(None) => self.flush(from).await,
+                Replication::Flush(Some(delay)) => {
+                    let Some(tx) = self.tx.clone() else {
+                        continue;
+                    };
+                    tokio::task::spawn(async move {
+                        tokio::time::sleep(delay).await;
+                        let _ = tx.send(ServerMessages::Flush).await;
+                    });
+                }
+            }
+        }
+    }
+
+    /// Drops every entry, logs that it did and tells every node link except `from` to do the
+    /// same.
+    async fn flush(&mut self, from: Option<PeerAddr>) {
+        self.db.flush().await;
+        if let Some(ref aof) = self.aof {
+            if let Err(err) = aof.append_flush().await {
+                tracing::error!(message = "Could not write to append-only log", %err);
+            }
+        }
+
+        let msg = ClientMessage::encode_replicate_flush();
+        for link in self.links_mut().filter(|l| Some(l.addr()) != from) {
+            link.send_messageb(&msg).await;
+        }
+    }
+
+    /// Sends our whole database to a node that just joined.
+    async fn sync_node(&mut self, addr: PeerAddr) {
+        let msg = self
+            .db
+            .entries()
+            .await
+            .into_iter()
+            .filter_map(|(key, data)| {
+                let value = data.inner()?;
+                Some(ClientMessage::encode_replication(
+                    &key,
+                    &value,
+                    data.remaining_ttl(),
+                    data.flags(),
+                ))
+            })
+            .flatten()
+            .collect::<Vec<u8>>();
+
+        if msg.is_empty() {
+            return;
+        }
+        if let Some(node) = self.nodes.iter_mut().find(|n| n.client.addr() == addr) {
+            node.client.send_messageb(&msg).await;
+        }
+    }
+
     /// Adds a new node to the topolgy if and only if the max_nodes is not reached. If the max_nodes
-    /// is reached, the trys to find the next node in line that can accept the connection.
-    pub async fn add_node(&mut self, client: Client) -> Result<&mut Client, Option<Client>> {
-        todo!()
+    /// is reached, the client is told to try the next node in line and handed back, so it stays
+    /// a normal client until it disconnects.
+    pub async fn add_node(
+        &mut self,
+        listen_addr: SocketAddr,
+        client: Client,
+    ) -> Result<&mut Client, Client> {
+        if self.nodes.len() >= self.config.max_nodes() as usize {
+            return Err(self.ask_deferred_node(client).await);
+        }
+
+        tracing::info!(message = "Node joined", %listen_addr);
+        self.nodes.push(ChildNode {
+            listen_addr,
+            client,
+        });
+        let node = self.nodes.last_mut().expect("node was just pushed");
+        Ok(&mut node.client)
+    }
+
+    /// Answers `DEFERED <addr>` with the listen address of one of our children. Children are
+    /// handed out round robin so the tree grows evenly instead of down a single branch.
+    async fn ask_deferred_node(&mut self, mut client: Client) -> Client {
+        if self.nodes.is_empty() {
+            tracing::warn!(message = "Refusing JOIN, max_nodes is 0");
+            client
+                .send_messageln("ERROR node does not accept children".to_string())
+                .await;
+            return client;
+        }
+
+        let child = &self.nodes[self.next_deferral % self.nodes.len()];
+        self.next_deferral = self.next_deferral.wrapping_add(1);
+        let msg = format!("DEFERED {}", child.listen_addr);
+        tracing::debug!(message = "Deferring JOIN", %msg);
+        client.send_messageln(msg).await;
+        client
     }
 
-    async fn ask_deferred_node(&mut self, client: Client) -> Option<Client> {
-        todo!()
+    /// Since the topology is a tree, losing a child only cuts off that child's subtree, the rest
+    /// of our children are left alone. Returns whether `addr` belonged to one of our children.
+    pub async fn drop_connection_to_nodes(&mut self, addr: PeerAddr) -> bool {
+        let Some(idx) = self.nodes.iter().position(|n| n.client.addr() == addr) else {
+            return false;
+        };
+        let node = self.nodes.remove(idx);
+        node.client.disconnect().await;
+        true
     }
+}
+
+/// Asks the server to reload its configuration whenever the process gets SIGHUP.
+fn reload_on_hangup(tx: Sender<ServerMessages>) {
+    use tokio::signal::unix::{signal, SignalKind};
 
-    /// Since the topology is a tree, we can drop the connection to the next node in line - starts
-    /// from index 0 and goes up to the max_nodes. the client will always be part of the connection
-    /// list as it's not possible to have a maxed out connection list.
-    ///
-    /// This function works like a pyramid scheme, where the last node in the list is the one that
-    /// gets the connection.
-    pub async fn drop_connection_to_nodes(&mut self, client: Client) {}
+    tokio::task::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(err) => {
+                tracing::error!(message = "Could not listen for SIGHUP", %err);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            tracing::info!("SIGHUP, reloading config");
+            if tx.send(ServerMessages::ReloadConfig).await.is_err() {
+                return;
+            }
+        }
+    });
+}
+
+/// The native reply to a `SET` or `CAS`: `STORED`, or why it wasn't. A failed `NX` or a stale
+/// token means the key `EXISTS` (again), a failed `XX` or `CAS` that it was `NOT_FOUND`.
+fn store_reply(mode: StoreMode, res: StoreResult) -> &'static str {
+    match (res, mode) {
+        (StoreResult::Stored, _) => "STORED",
+        (StoreResult::OutOfMemory, _) => "ERROR out of memory",
+        (_, StoreMode::Add) | (StoreResult::Exists, _) => "EXISTS",
+        (_, StoreMode::Replace) | (StoreResult::NotFound, _) => "NOT_FOUND",
+        (StoreResult::NotStored, _) => "NOT_STORED",
+    }
 }
@@ -0,0 +1,211 @@
+use std::{
+    io,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+};
+
+use crate::{database::Database, frame::MAX_VALUE_LEN};
+
+/// Start of every snapshot file.
+const MAGIC: &[u8; 8] = b"RSCACHE\0";
+/// Bumped whenever the layout below changes, older versions are refused on load.
+const VERSION: u16 = 1;
+
+// Layout, integers big endian:
+//
+//   MAGIC VERSION:u16 SAVED_AT_MILLIS:u64 COUNT:u64
+//   COUNT times: KEY_LEN:u32 KEY FLAGS:u32 TTL_MILLIS:i64 VALUE_LEN:u32 VALUE
+//
+// TTL_MILLIS is what the entry had left when it was saved, -1 if it never expires.
+
+/// Numbers the temporary files, so a `BGSAVE` and the periodic save running at the same time
+/// don't write into the same one.
+static SAVES: AtomicU64 = AtomicU64::new(0);
+
+/// Writes every live entry to `path`, through a temporary file so a crash mid-save leaves the
+/// previous snapshot intact. Returns how many entries were written.
+pub async fn save(db: &Database, path: &Path) -> io::Result<usize> {
+    let tmp = path.with_extension(format!("tmp{}", SAVES.fetch_add(1, Ordering::Relaxed)));
+    let res = write(db, path, &tmp).await;
+    if res.is_err() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+    }
+    res
+}
+
+async fn write(db: &Database, path: &Path, tmp: &Path) -> io::Result<usize> {
+    let entries = db.entries().await;
+    let mut out = BufWriter::new(File::create(&tmp).await?);
+    out.write_all(MAGIC).await?;
+    out.write_u16(VERSION).await?;
+    out.write_u64(unix_millis(SystemTime::now())).await?;
+    out.write_u64(entries.len() as u64).await?;
+    for (key, data) in &entries {
+        let value = data.inner().unwrap_or_default();
+        let ttl = data
+            .remaining_ttl()
+            .map_or(-1, |ttl| ttl.as_millis() as i64);
+
+        out.write_u32(key.len() as u32).await?;
+        out.write_all(key.as_bytes()).await?;
+        out.write_u32(data.flags()).await?;
+        out.write_i64(ttl).await?;
+        out.write_u32(value.len() as u32).await?;
+        out.write_all(&value).await?;
+    }
+    out.flush().await?;
+    out.into_inner().sync_all().await?;
+
+    tokio::fs::rename(tmp, path).await?;
+    Ok(entries.len())
+}
+
+/// Reads a snapshot back into `db`. The time the node was down counts against the ttls, entries
+/// that ran out in the meantime are skipped. Returns how many entries were loaded.
+pub async fn load(db: &Database, path: &Path) -> io::Result<usize> {
+    let mut input = BufReader::new(File::open(path).await?);
+
+    let mut magic = [0; MAGIC.len()];
+    input.read_exact(&mut magic).await?;
+    if &magic != MAGIC {
+        return Err(invalid("not an rscache snapshot"));
+    }
+    let version = input.read_u16().await?;
+    if version != VERSION {
+        return Err(invalid(format!("unsupported snapshot version {}", version)));
+    }
+    let saved_at = input.read_u64().await?;
+    let downtime = Duration::from_millis(unix_millis(SystemTime::now()).saturating_sub(saved_at));
+
+    let count = input.read_u64().await?;
+    let mut loaded = 0;
+    for _ in 0..count {
+        let key = read_bytes(&mut input).await?;
+        let key = String::from_utf8(key).map_err(|_| invalid("key is not UTF-8"))?;
+        let flags = input.read_u32().await?;
+        let ttl = input.read_i64().await?;
+        let value = read_bytes(&mut input).await?;
+
+        let ttl = match u64::try_from(ttl) {
+            Ok(ttl) => match Duration::from_millis(ttl).checked_sub(downtime) {
+                Some(ttl) if !ttl.is_zero() => Some(ttl),
+                _ => continue,
+            },
+            Err(_) => None,
+        };
+        db.insert_replica(key, value, flags, ttl).await;
+        loaded += 1;
+    }
+    Ok(loaded)
+}
+
+async fn read_bytes(input: &mut BufReader<File>) -> io::Result<Vec<u8>> {
+    let len = input.read_u32().await?;
+    if len as usize > MAX_VALUE_LEN {
+        return Err(invalid(format!("entry of {} bytes", len)));
+    }
+    let mut buf = vec![0; len as usize];
+    input.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::*;
+    use crate::database::StoreMode;
+
+    fn new_db() -> Database {
+        Database::new(Arc::new(serde_json::from_str("{}").expect("valid config")))
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rscache-{}-{}", std::process::id(), name))
+    }
+
+    #[tokio::test]
+    async fn entries_survive_a_round_trip() {
+        let path = temp_path("round-trip.rdb");
+        let db = new_db();
+        let ttl = Some(Duration::from_secs(60));
+        db.store("a".into(), b"1\n2".to_vec(), 7, None, StoreMode::Set)
+            .await;
+        db.store("b".into(), vec![], 0, ttl, StoreMode::Set).await;
+        db.store(
+            "gone".into(),
+            b"x".to_vec(),
+            0,
+            Some(Duration::ZERO),
+            StoreMode::Set,
+        )
+        .await;
+        assert_eq!(save(&db, &path).await.unwrap(), 2);
+
+        let loaded = new_db();
+        assert_eq!(load(&loaded, &path).await.unwrap(), 2);
+        let a = loaded.get_or_remove("a".into()).await.unwrap();
+        assert_eq!((a.inner().unwrap(), a.flags()), (b"1\n2".to_vec(), 7));
+        assert_eq!(a.remaining_ttl(), None);
+        let b = loaded.get_or_remove("b".into()).await.unwrap();
+        assert!(b
+            .remaining_ttl()
+            .is_some_and(|ttl| ttl > Duration::from_secs(59)));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn downtime_counts_against_the_ttls() {
+        let path = temp_path("downtime.rdb");
+        let db = new_db();
+        let secs = |n| Some(Duration::from_secs(n));
+        db.store("short".into(), b"v".to_vec(), 0, secs(5), StoreMode::Set)
+            .await;
+        db.store("long".into(), b"v".to_vec(), 0, secs(60), StoreMode::Set)
+            .await;
+        db.store("forever".into(), b"v".to_vec(), 0, None, StoreMode::Set)
+            .await;
+        save(&db, &path).await.unwrap();
+
+        // as if the node went down ten seconds ago, right after saving
+        let mut bytes = std::fs::read(&path).unwrap();
+        let at = MAGIC.len() + 2;
+        let saved_at = u64::from_be_bytes(bytes[at..at + 8].try_into().unwrap());
+        bytes[at..at + 8].copy_from_slice(&(saved_at - 10_000).to_be_bytes());
+        std::fs::write(&path, bytes).unwrap();
+
+        let loaded = new_db();
+        assert_eq!(load(&loaded, &path).await.unwrap(), 2);
+        assert!(loaded.get_or_remove("short".into()).await.is_none());
+        let long = loaded.get_or_remove("long".into()).await.unwrap();
+        assert!(long
+            .remaining_ttl()
+            .is_some_and(|ttl| ttl <= Duration::from_secs(50)));
+        assert!(loaded.get_or_remove("forever".into()).await.is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn other_files_are_refused() {
+        let path = temp_path("not-a-snapshot.rdb");
+        std::fs::write(&path, b"*1\r\n$4\r\nPING\r\n").unwrap();
+        let err = load(&new_db(), &path).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+        server::WebPkiClientVerifier,
+        ClientConfig, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor, TlsConnector,
+};
+
+use crate::{client::Connection, config::Config};
+
+/// How long a peer gets to finish the handshake, so a silent one can't hold its task and socket.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TLS for the client port and the links between nodes. Nodes present their certificate on both
+/// ends of a link and check the other's against `tls_ca`, clients may connect without one.
+#[derive(Clone)]
+pub struct Tls {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+}
+
+/// A connection accepted on the client port.
+pub struct Accepted {
+    pub stream: Box<dyn Connection>,
+    /// The peer presented a certificate signed by `tls_ca`, which a node needs to `JOIN`
+    pub verified: bool,
+}
+
+impl Tls {
+    /// Reads the certificates and key named in `config`. `None` if TLS isn't configured.
+    pub fn load(config: &Config) -> io::Result<Option<Self>> {
+        let (Some(cert), Some(key), Some(ca)) =
+            (config.tls_cert(), config.tls_key(), config.tls_ca())
+        else {
+            return Ok(None);
+        };
+        let certs = read_certs(cert)?;
+        let key = read_key(key)?;
+
+        let mut roots = RootCertStore::empty();
+        for ca in read_certs(ca)? {
+            roots.add(ca).map_err(invalid)?;
+        }
+        let roots = Arc::new(roots);
+
+        let verifier = WebPkiClientVerifier::builder(Arc::clone(&roots))
+            .allow_unauthenticated()
+            .build()
+            .map_err(invalid)?;
+        let server = ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs.clone(), key.clone_key())
+            .map_err(invalid)?;
+        let client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(certs, key)
+            .map_err(invalid)?;
+
+        Ok(Some(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server)),
+            connector: TlsConnector::from(Arc::new(client)),
+        }))
+    }
+
+    /// Does the server side of the handshake on a connection to the client port.
+    pub async fn accept(&self, stream: TcpStream) -> io::Result<Accepted> {
+        let stream = with_timeout(self.acceptor.accept(stream)).await?;
+        let verified = stream.get_ref().1.peer_certificates().is_some();
+        Ok(Accepted {
+            stream: Box::new(stream),
+            verified,
+        })
+    }
+
+    /// Connects to another node, which has to present a certificate signed by `tls_ca` for
+    /// `name`: the host the parent was configured with, or the address we were deferred to.
+    pub async fn connect(
+        &self,
+        stream: TcpStream,
+        name: ServerName<'static>,
+    ) -> io::Result<Box<dyn Connection>> {
+        Ok(Box::new(
+            with_timeout(self.connector.connect(name, stream)).await?,
+        ))
+    }
+}
+
+async fn with_timeout<T>(
+    handshake: impl std::future::Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))?
+}
+
+/// What a node's certificate has to be issued for when we reach it at `addr`.
+pub fn server_name(host: &str, addr: SocketAddr) -> ServerName<'static> {
+    ServerName::try_from(host.to_string()).unwrap_or_else(|_| ServerName::from(addr.ip()))
+}
+
+fn read_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut input = BufReader::new(File::open(path).map_err(|err| with_path(path, err))?);
+    let certs = rustls_pemfile::certs(&mut input)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| with_path(path, err))?;
+    match certs.is_empty() {
+        true => Err(with_path(path, invalid("no certificates found"))),
+        false => Ok(certs),
+    }
+}
+
+fn read_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut input = BufReader::new(File::open(path).map_err(|err| with_path(path, err))?);
+    rustls_pemfile::private_key(&mut input)
+        .map_err(|err| with_path(path, err))?
+        .ok_or_else(|| with_path(path, invalid("no private key found")))
+}
+
+fn with_path(path: &Path, err: io::Error) -> io::Error {
+    io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+}
+
+fn invalid(err: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}